@@ -0,0 +1,747 @@
+use crate::config::{self, AnkiField};
+use akasabi::Entry;
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use rusqlite::{params, Connection};
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+const DEFAULT_DECK: &str = "akasabi";
+const DEFAULT_MODEL: &str = "akasabi (JMdict)";
+const DEFAULT_CONNECT_URL: &str = "http://127.0.0.1:8765";
+
+/// Anki settings from the config file, with defaults filled in.
+#[derive(Debug, Clone)]
+pub(crate) struct Settings {
+    pub(crate) deck: String,
+    pub(crate) model: String,
+    pub(crate) fields: Vec<AnkiField>,
+    pub(crate) connect_url: String,
+}
+
+impl Settings {
+    pub(crate) fn from_config(anki: &config::Anki) -> Self {
+        let fields = if anki.fields.is_empty() {
+            [
+                ("Expression", "{headword}"),
+                ("Reading", "{reading}"),
+                ("Meaning", "{senses}"),
+                ("Part of speech", "{pos}"),
+                ("ID", "{id}"),
+            ]
+            .into_iter()
+            .map(|(name, template)| AnkiField {
+                name: name.to_string(),
+                template: template.to_string(),
+            })
+            .collect()
+        } else {
+            anki.fields.clone()
+        };
+
+        Settings {
+            deck: anki
+                .deck
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DECK.to_string()),
+            model: anki
+                .model
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            fields,
+            connect_url: anki
+                .connect_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONNECT_URL.to_string()),
+        }
+    }
+
+    fn field_names(&self) -> Vec<&str> {
+        self.fields.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    /// The first field goes on the front of the card...
+    fn front_template(&self) -> String {
+        format!("{{{{{}}}}}", self.field_names().first().unwrap_or(&""))
+    }
+
+    /// ...and everything else on the back.
+    fn back_template(&self) -> String {
+        let back = self
+            .field_names()
+            .iter()
+            .skip(1)
+            .map(|name| format!("{{{{{name}}}}}"))
+            .join("<br>");
+        format!("{{{{FrontSide}}}}<hr id=answer>{back}")
+    }
+}
+
+/// A note, with fields in the same order as [`Settings::fields`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Note {
+    /// Stable across exports, so re-importing updates notes instead of duplicating them
+    pub(crate) guid: String,
+    pub(crate) fields: Vec<String>,
+}
+
+impl Note {
+    pub(crate) fn from_entry(entry: &Entry, settings: &Settings) -> Self {
+        let id = entry.id;
+        let kanji = entry.kanji.iter().map(|k| k.text.as_str()).collect_vec();
+        let readings = entry.readings.iter().map(|r| r.text.as_str()).collect_vec();
+        let poses = entry.senses.iter().map(|s| s.pos.join("; ")).collect_vec();
+
+        let headword = entry.headword();
+        let senses = entry
+            .senses
+            .iter()
+            .zip(&poses)
+            .map(|(sense, pos)| {
+                let meaning = sense.glosses.join("; ");
+                if pos.is_empty() {
+                    format!("<li>{}</li>", escape(&meaning))
+                } else {
+                    format!(
+                        "<li><span class=\"pos\">{}</span> {}</li>",
+                        escape(pos),
+                        escape(&meaning)
+                    )
+                }
+            })
+            .join("");
+
+        let replacements = [
+            ("{headword}", escape(headword)),
+            ("{kanji}", escape(&kanji.join("; "))),
+            ("{reading}", escape(&readings.join("、"))),
+            ("{senses}", format!("<ol>{senses}</ol>")),
+            (
+                "{pos}",
+                escape(&poses.iter().filter(|p| !p.is_empty()).unique().join("; ")),
+            ),
+            ("{id}", id.map(|id| id.to_string()).unwrap_or_default()),
+        ];
+        let fields = settings
+            .fields
+            .iter()
+            .map(|field| {
+                replacements
+                    .iter()
+                    .fold(field.template.clone(), |acc, (key, value)| {
+                        acc.replace(key, value)
+                    })
+            })
+            .collect();
+
+        let guid = match id {
+            Some(id) => format!("akasabi-{id}"),
+            None => format!(
+                "akasabi-{}",
+                sha1_hex(&format!("{headword}\x1f{}", readings.join("、")))
+            ),
+        };
+
+        Note { guid, fields }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn sha1_hex(s: &str) -> String {
+    sha1_smol::Sha1::from(s).digest().to_string()
+}
+
+/// Derives an id from a name, so repeated exports land in the same deck and note type.
+fn stable_id(name: &str) -> i64 {
+    // Keep it well within what JavaScript (and thus AnkiWeb) can represent
+    i64::from_str_radix(&sha1_hex(name)[..12], 16).unwrap()
+}
+
+/// Anki's checksum of the first field, used for duplicate detection.
+fn field_checksum(field: &str) -> i64 {
+    i64::from_str_radix(&sha1_hex(&strip_html(field))[..8], 16).unwrap()
+}
+
+fn strip_html(s: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Tables from Anki's collection schema, version 11.
+const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+    ver integer not null, dty integer not null, usn integer not null, ls integer not null,
+    conf text not null, models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null, mod integer not null,
+    usn integer not null, tags text not null, flds text not null, sfld integer not null,
+    csum integer not null, flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null, ord integer not null,
+    mod integer not null, usn integer not null, type integer not null, queue integer not null,
+    due integer not null, ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null, odid integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null,
+    type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+const CSS: &str = ".card { font-family: sans-serif; font-size: 20px; text-align: center; }
+ol { text-align: left; }
+.pos { color: #b58900; font-size: 0.8em; }";
+
+/// Writes the notes to a deck package that Anki can import directly.
+pub(crate) fn write_apkg<P: AsRef<Path>>(
+    path: P,
+    settings: &Settings,
+    notes: &[Note],
+) -> Result<()> {
+    let collection = tempfile::NamedTempFile::new()?;
+    write_collection(&Connection::open(collection.path())?, settings, notes)?;
+
+    let mut archive = ZipWriter::new(BufWriter::new(File::create(path)?));
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    archive.start_file("collection.anki2", options)?;
+    std::io::copy(&mut File::open(collection.path())?, &mut archive)?;
+    // No media, but Anki expects the (empty) mapping of media file names
+    archive.start_file("media", options)?;
+    archive.write_all(b"{}")?;
+    archive.finish()?.flush()?;
+
+    Ok(())
+}
+
+fn write_collection(conn: &Connection, settings: &Settings, notes: &[Note]) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let (now_secs, now_millis) = (
+        i64::try_from(now.as_secs())?,
+        i64::try_from(now.as_millis())?,
+    );
+
+    let deck_id = stable_id(&settings.deck);
+    let field_names = settings.field_names();
+    let model_id = stable_id(&format!(
+        "{}\x1f{}",
+        settings.model,
+        field_names.join("\x1f")
+    ));
+
+    let deck = |id: i64, name: &str| {
+        json!({
+            "id": id, "name": name, "mod": now_secs, "usn": -1, "desc": "", "dyn": 0, "conf": 1,
+            "collapsed": false, "extendNew": 10, "extendRev": 50,
+            "lrnToday": [0, 0], "revToday": [0, 0], "newToday": [0, 0], "timeToday": [0, 0],
+        })
+    };
+    let decks =
+        json!({"1": deck(1, "Default"), deck_id.to_string(): deck(deck_id, &settings.deck)});
+
+    let model = json!({
+        "id": model_id, "name": settings.model, "type": 0, "mod": now_secs, "usn": -1, "sortf": 0,
+        "did": deck_id, "css": CSS, "tags": [], "vers": [],
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "req": [[0, "any", [0]]],
+        "flds": field_names.iter().enumerate().map(|(ord, name)| json!({
+            "name": name, "ord": ord, "sticky": false, "rtl": false,
+            "font": "Arial", "size": 20, "media": [],
+        })).collect_vec(),
+        "tmpls": [{
+            "name": "Recognition", "ord": 0, "did": null, "bqfmt": "", "bafmt": "",
+            "qfmt": settings.front_template(),
+            "afmt": settings.back_template(),
+        }],
+    });
+    let models = json!({model_id.to_string(): model});
+
+    let col_conf = json!({
+        "activeDecks": [1], "curDeck": deck_id, "newSpread": 0, "collapseTime": 1200,
+        "timeLim": 0, "estTimes": true, "dueCounts": true, "curModel": model_id.to_string(),
+        "nextPos": notes.len() + 1, "sortType": "noteFld", "sortBackwards": false,
+        "addToCur": true,
+    });
+    let dconf = json!({"1": {
+        "id": 1, "name": "Default", "mod": 0, "usn": 0, "maxTaken": 60, "autoplay": true,
+        "timer": 0, "replayq": true, "dyn": false,
+        "new": {"delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500, "separate": true,
+                "order": 1, "perDay": 20, "bury": false},
+        "lapse": {"delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0},
+        "rev": {"perDay": 200, "ease4": 1.3, "fuzz": 0.05, "minSpace": 1, "ivlFct": 1,
+                "maxIvl": 36500, "bury": false, "hardFactor": 1.2},
+    }});
+
+    conn.execute_batch(SCHEMA)?;
+    conn.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+        params![
+            now_secs - now_secs % 86_400,
+            now_millis,
+            col_conf.to_string(),
+            models.to_string(),
+            decks.to_string(),
+            dconf.to_string()
+        ],
+    )?;
+
+    for (idx, note) in (0_i64..).zip(notes) {
+        // Note and card ids are creation times in milliseconds, and have to be unique
+        let id = now_millis + idx;
+        let first_field = note.fields.first().map(String::as_str).unwrap_or_default();
+        conn.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ' akasabi ', ?5, ?6, ?7, 0, '')",
+            params![
+                id,
+                note.guid,
+                model_id,
+                now_secs,
+                note.fields.join("\x1f"),
+                strip_html(first_field),
+                field_checksum(first_field)
+            ],
+        )?;
+        conn.execute(
+            "INSERT INTO cards VALUES (?1, ?1, ?2, 0, ?3, -1, 0, 0, ?4, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![id, deck_id, now_secs, idx + 1],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Outcome of adding a note through `AnkiConnect`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AddResult {
+    Added(i64),
+    /// The note was already in the collection, with these note ids
+    Duplicate(Vec<i64>),
+}
+
+/// Client for the `AnkiConnect` add-on, for adding notes to a running Anki.
+pub(crate) struct AnkiConnect {
+    url: String,
+    client: reqwest::blocking::Client,
+}
+
+impl AnkiConnect {
+    pub(crate) fn new(url: &str) -> Self {
+        AnkiConnect {
+            url: url.to_string(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn request(&self, action: &str, params: &serde_json::Value) -> Result<serde_json::Value> {
+        let body = json!({"action": action, "version": 6, "params": params});
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .with_context(|| format!("Failed to connect to AnkiConnect at {}", self.url))?
+            .error_for_status()?
+            .text()?;
+
+        let mut response: serde_json::Value = serde_json::from_str(&response)?;
+        match response["error"].as_str() {
+            Some(error) => bail!("AnkiConnect {action} failed: {error}"),
+            None => Ok(response["result"].take()),
+        }
+    }
+
+    /// Creates the deck and note type if they don't exist yet.
+    pub(crate) fn prepare(&self, settings: &Settings) -> Result<()> {
+        // createDeck is a no-op for existing decks
+        self.request("createDeck", &json!({"deck": settings.deck}))?;
+
+        let models = self.request("modelNames", &json!({}))?;
+        let exists = models
+            .as_array()
+            .is_some_and(|models| models.iter().any(|m| m == settings.model.as_str()));
+        if !exists {
+            self.request(
+                "createModel",
+                &json!({
+                    "modelName": settings.model,
+                    "inOrderFields": settings.field_names(),
+                    "css": CSS,
+                    "cardTemplates": [{
+                        "Name": "Recognition",
+                        "Front": settings.front_template(),
+                        "Back": settings.back_template(),
+                    }],
+                }),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a note, unless one with the same first field already exists in the deck.
+    pub(crate) fn add_note(&self, settings: &Settings, note: &Note) -> Result<AddResult> {
+        let (Some(first_name), Some(first_value)) = (settings.fields.first(), note.fields.first())
+        else {
+            bail!("The note type has no fields");
+        };
+        let query = format!(
+            "{} {} {}",
+            search_term("deck", &settings.deck),
+            search_term("note", &settings.model),
+            search_term(&first_name.name, &strip_html(first_value)),
+        );
+        let existing = self.request("findNotes", &json!({ "query": query }))?;
+        let existing = existing
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_i64)
+            .collect_vec();
+        if !existing.is_empty() {
+            return Ok(AddResult::Duplicate(existing));
+        }
+
+        let fields: serde_json::Map<_, _> = settings
+            .fields
+            .iter()
+            .zip(&note.fields)
+            .map(|(field, value)| (field.name.clone(), json!(value)))
+            .collect();
+        let id = self.request(
+            "addNote",
+            &json!({"note": {
+                "deckName": settings.deck,
+                "modelName": settings.model,
+                "fields": fields,
+                "tags": ["akasabi"],
+            }}),
+        )?;
+
+        id.as_i64()
+            .map(AddResult::Added)
+            .ok_or_else(|| anyhow!("AnkiConnect returned an invalid note id: {id}"))
+    }
+}
+
+/// Quotes a `name:value` term for Anki's search syntax.
+fn search_term(name: &str, value: &str) -> String {
+    let escape = |s: &str| {
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('*', "\\*")
+            .replace('_', "\\_")
+            .replace(':', "\\:")
+    };
+    format!("\"{}:{}\"", escape(name), escape(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    fn test_settings() -> Settings {
+        Settings::from_config(&config::Anki::default())
+    }
+
+    #[test]
+    fn test_note_from_entry() {
+        let entry = Entry {
+            id: Some(1_829_380),
+            kanji: vec![akasabi::KanjiForm {
+                text: "赤錆".to_string(),
+            }],
+            readings: vec![akasabi::Reading {
+                text: "あかさび".to_string(),
+            }],
+            senses: vec![akasabi::Sense {
+                glosses: vec!["rust".to_string(), "<red> rust".to_string()],
+                pos: vec!["noun (common) (futsuumeishi)".to_string()],
+                ..akasabi::Sense::default()
+            }],
+            ..Entry::default()
+        };
+
+        let note = Note::from_entry(&entry, &test_settings());
+
+        assert_eq!(note.guid, "akasabi-1829380");
+        assert_eq!(
+            note.fields,
+            vec![
+                "赤錆",
+                "あかさび",
+                "<ol><li><span class=\"pos\">noun (common) (futsuumeishi)</span> rust; &lt;red&gt; rust</li></ol>",
+                "noun (common) (futsuumeishi)",
+                "1829380",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_apkg() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.apkg");
+        let notes = vec![
+            Note {
+                guid: "akasabi-1".into(),
+                fields: vec![
+                    "赤錆".into(),
+                    "あかさび".into(),
+                    "rust".into(),
+                    String::new(),
+                    "1".into(),
+                ],
+            },
+            Note {
+                guid: "akasabi-2".into(),
+                fields: vec![
+                    "<b>錆</b>".into(),
+                    "さび".into(),
+                    "rust".into(),
+                    String::new(),
+                    "2".into(),
+                ],
+            },
+        ];
+        write_apkg(&path, &test_settings(), &notes).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        assert_eq!(media, "{}");
+
+        let collection = dir.path().join("collection.anki2");
+        std::io::copy(
+            &mut archive.by_name("collection.anki2").unwrap(),
+            &mut File::create(&collection).unwrap(),
+        )
+        .unwrap();
+        let conn = Connection::open(&collection).unwrap();
+
+        let rows: Vec<(String, String, String)> = conn
+            .prepare("SELECT guid, flds, sfld FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].0, "akasabi-1");
+        assert_eq!(rows[0].1, "赤錆\x1fあかさび\x1frust\x1f\x1f1");
+        assert_eq!(rows[1].2, "錆");
+
+        let cards: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM cards JOIN notes ON cards.nid = notes.id",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(cards, 2);
+
+        let models: String = conn
+            .query_row("SELECT models FROM col", [], |row| row.get(0))
+            .unwrap();
+        let models: serde_json::Value = serde_json::from_str(&models).unwrap();
+        let model = models.as_object().unwrap().values().next().unwrap();
+        assert_eq!(model["name"], DEFAULT_MODEL);
+        assert_eq!(model["flds"].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_field_checksum() {
+        // As computed by Anki: int(sha1("赤錆").hexdigest()[:8], 16)
+        assert_eq!(field_checksum("<b>赤錆</b>"), 1_278_081_021);
+    }
+
+    #[test]
+    fn test_search_term() {
+        assert_eq!(search_term("deck", "a*b_c"), r#""deck:a\*b\_c""#);
+        assert_eq!(
+            search_term("Front: 1", r#"say "hi""#),
+            r#""Front\: 1:say \"hi\"""#
+        );
+    }
+
+    /// Serves a minimal `AnkiConnect` API on a local port, recording every request.
+    fn mock_anki_connect() -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+
+        std::thread::spawn(move || {
+            let mut models = vec![json!("Basic")];
+            let mut notes: Vec<String> = Vec::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                let params = &request["params"];
+                let result = match request["action"].as_str().unwrap() {
+                    "modelNames" => json!(models),
+                    "createModel" => {
+                        models.push(params["modelName"].clone());
+                        json!({})
+                    }
+                    "findNotes" => {
+                        let query = params["query"].as_str().unwrap();
+                        json!((1..=notes.len())
+                            .filter(|&id| query.contains(&format!(":{}\"", notes[id - 1])))
+                            .collect_vec())
+                    }
+                    "addNote" => {
+                        let fields = &params["note"]["fields"];
+                        notes.push(fields["Expression"].as_str().unwrap().to_string());
+                        json!(notes.len())
+                    }
+                    _ => serde_json::Value::Null,
+                };
+                log.lock().unwrap().push(request);
+
+                let response = json!({"result": result, "error": null}).to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn test_anki_connect() {
+        let (url, requests) = mock_anki_connect();
+        let settings = test_settings();
+        let connect = AnkiConnect::new(&url);
+        let note = |id: i64, word: &str| Note {
+            guid: format!("akasabi-{id}"),
+            fields: vec![
+                word.to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                id.to_string(),
+            ],
+        };
+
+        connect.prepare(&settings).unwrap();
+        assert!(matches!(
+            connect.add_note(&settings, &note(1, "赤錆")).unwrap(),
+            AddResult::Added(1)
+        ));
+        assert!(matches!(
+            connect.add_note(&settings, &note(2, "錆")).unwrap(),
+            AddResult::Added(2)
+        ));
+        match connect.add_note(&settings, &note(1, "赤錆")).unwrap() {
+            AddResult::Duplicate(ids) => assert_eq!(ids, vec![1]),
+            AddResult::Added(_) => panic!("duplicate note was added"),
+        }
+        // The note type already exists now, so it's not created again
+        connect.prepare(&settings).unwrap();
+
+        let requests = requests.lock().unwrap();
+        let actions = requests
+            .iter()
+            .map(|request| request["action"].as_str().unwrap())
+            .collect_vec();
+        assert_eq!(
+            actions,
+            vec![
+                "createDeck",
+                "modelNames",
+                "createModel",
+                "findNotes",
+                "addNote",
+                "findNotes",
+                "addNote",
+                "findNotes",
+                "createDeck",
+                "modelNames",
+            ]
+        );
+        assert!(requests.iter().all(|request| request["version"] == 6));
+        assert_eq!(requests[1]["params"], json!({}));
+        assert_eq!(
+            requests[2]["params"]["inOrderFields"],
+            json!(["Expression", "Reading", "Meaning", "Part of speech", "ID"])
+        );
+        assert_eq!(
+            requests[3]["params"]["query"],
+            r#""deck:akasabi" "note:akasabi (JMdict)" "Expression:赤錆""#
+        );
+        assert_eq!(requests[4]["params"]["note"]["deckName"], "akasabi");
+        assert_eq!(requests[4]["params"]["note"]["fields"]["ID"], "1");
+    }
+
+    #[test]
+    fn test_anki_connect_error() {
+        let connect = AnkiConnect::new("http://127.0.0.1:1");
+        let error = connect.prepare(&test_settings()).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Failed to connect to AnkiConnect"));
+    }
+}
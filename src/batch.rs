@@ -0,0 +1,364 @@
+use akasabi::{Dictionary, Entry, SearchOptions};
+use anyhow::Result;
+use itertools::Itertools;
+use std::fmt;
+use std::io::{BufRead, Write};
+use wana_kana::{ConvertJapanese, IsJapaneseStr};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Column {
+    Input,
+    Kanji,
+    Reading,
+    Glosses,
+    Pos,
+    Id,
+    Confidence,
+}
+
+impl Column {
+    fn header(self) -> &'static str {
+        match self {
+            Column::Input => "input",
+            Column::Kanji => "kanji",
+            Column::Reading => "reading",
+            Column::Glosses => "glosses",
+            Column::Pos => "pos",
+            Column::Id => "ent_seq",
+            Column::Confidence => "confidence",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Tsv,
+    Csv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Confidence {
+    /// The input is a headword or reading of exactly one result
+    Exact,
+    /// The input is a headword or reading of several results
+    Ambiguous,
+    /// The best result doesn't have the input as a headword or reading
+    Partial,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Confidence::Exact => "exact",
+            Confidence::Ambiguous => "ambiguous",
+            Confidence::Partial => "partial",
+        })
+    }
+}
+
+/// The entry chosen for one input word.
+pub(crate) struct Lookup {
+    pub(crate) input: String,
+    pub(crate) confidence: Confidence,
+    pub(crate) entry: Entry,
+    /// Ids of all exact matches, when ambiguous
+    pub(crate) candidates: Vec<i64>,
+}
+
+/// Reads one word per line, skipping blank lines.
+pub(crate) fn read_words<R: BufRead>(reader: R) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let word = line.trim();
+        if !word.is_empty() {
+            words.push(word.to_string());
+        }
+    }
+    Ok(words)
+}
+
+/// Looks up one input word. Kana is searched for in both hiragana and
+/// katakana, as the index only matches readings the way they're written.
+// TODO: Deinflect conjugated words (eg. 食べた to 食べる) that aren't found
+// as they are. The rules could follow Yomitan's, which `deinflection_rule`
+// in the indexer already maps JMdict's parts of speech to.
+pub(crate) fn lookup(dictionary: &Dictionary, input: &str) -> Result<Option<Lookup>> {
+    let mut forms = vec![input.to_string()];
+    if input.is_kana() {
+        forms.extend([input.to_hiragana(), input.to_katakana()]);
+    }
+    let mut results = Vec::new();
+    for form in forms.iter().unique() {
+        let options = SearchOptions::new().literal(true);
+        let found = match dictionary.search(form, &options) {
+            Ok(found) => found,
+            // Treat words that aren't valid queries as not found
+            Err(e) if e.is::<akasabi::QueryError>() => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for result in found {
+            if !results.contains(&result.entry) {
+                results.push(result.entry);
+            }
+        }
+    }
+    Ok(choose(input, results))
+}
+
+/// Picks the best of the search results (in score order) for an input word,
+/// preferring results that have the input as a headword or reading.
+/// Kana is compared without regard to hiragana/katakana/romaji.
+pub(crate) fn choose(input: &str, results: Vec<Entry>) -> Option<Lookup> {
+    let normalized = input.to_katakana();
+    let is_exact = |entry: &Entry| {
+        entry
+            .kanji
+            .iter()
+            .map(|k| &k.text)
+            .chain(entry.readings.iter().map(|r| &r.text))
+            .any(|form| form == input || form.to_katakana() == normalized)
+    };
+
+    let (exact, rest): (Vec<_>, Vec<_>) = results.into_iter().partition(is_exact);
+    let candidates = exact.iter().filter_map(|entry| entry.id).collect_vec();
+    let confidence = match exact.len() {
+        0 => Confidence::Partial,
+        1 => Confidence::Exact,
+        _ => Confidence::Ambiguous,
+    };
+
+    exact.into_iter().chain(rest).next().map(|entry| Lookup {
+        input: input.to_string(),
+        confidence,
+        entry,
+        candidates: if confidence == Confidence::Ambiguous {
+            candidates
+        } else {
+            Vec::new()
+        },
+    })
+}
+
+/// Writes lookups as TSV or CSV rows.
+pub(crate) struct Writer<W: Write> {
+    inner: W,
+    format: Format,
+    columns: Vec<Column>,
+    glosses: usize,
+}
+
+impl<W: Write> Writer<W> {
+    pub(crate) fn new(inner: W, format: Format, columns: Vec<Column>, glosses: usize) -> Self {
+        Writer {
+            inner,
+            format,
+            columns,
+            glosses,
+        }
+    }
+
+    pub(crate) fn write_header(&mut self) -> Result<()> {
+        let header = self
+            .columns
+            .iter()
+            .map(|c| c.header().to_string())
+            .collect_vec();
+        self.write_record(&header)
+    }
+
+    pub(crate) fn write_lookup(&mut self, lookup: &Lookup) -> Result<()> {
+        let entry = &lookup.entry;
+        let record = self
+            .columns
+            .iter()
+            .map(|column| match column {
+                Column::Input => lookup.input.clone(),
+                Column::Kanji => entry.kanji.iter().map(|k| &k.text).join("; "),
+                Column::Reading => entry.readings.iter().map(|r| &r.text).join("、"),
+                Column::Glosses => entry
+                    .senses
+                    .iter()
+                    .flat_map(|sense| &sense.glosses)
+                    .take(self.glosses)
+                    .join("; "),
+                Column::Pos => entry
+                    .senses
+                    .iter()
+                    .find(|sense| !sense.pos.is_empty())
+                    .map(|sense| sense.pos.join("; "))
+                    .unwrap_or_default(),
+                Column::Id => entry.id.map(|id| id.to_string()).unwrap_or_default(),
+                Column::Confidence => lookup.confidence.to_string(),
+            })
+            .collect_vec();
+        self.write_record(&record)
+    }
+
+    fn write_record(&mut self, record: &[String]) -> Result<()> {
+        let line = match self.format {
+            // TSV has no quoting, so tabs and newlines become spaces
+            Format::Tsv => record
+                .iter()
+                .map(|value| value.replace(['\t', '\n', '\r'], " "))
+                .join("\t"),
+            Format::Csv => record
+                .iter()
+                .map(|value| {
+                    if value.contains([',', '"', '\n', '\r']) {
+                        format!("\"{}\"", value.replace('"', "\"\""))
+                    } else {
+                        value.clone()
+                    }
+                })
+                .join(","),
+        };
+        // CSV (RFC 4180) uses CRLF line endings
+        match self.format {
+            Format::Tsv => writeln!(self.inner, "{line}")?,
+            Format::Csv => write!(self.inner, "{line}\r\n")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(id: i64, words: &[&str], readings: &[&str]) -> Entry {
+        let strings = |values: &[&str]| values.iter().map(ToString::to_string).collect_vec();
+        Entry {
+            id: Some(id),
+            kanji: words
+                .iter()
+                .map(|text| akasabi::KanjiForm {
+                    text: text.to_string(),
+                })
+                .collect(),
+            readings: readings
+                .iter()
+                .map(|text| akasabi::Reading {
+                    text: text.to_string(),
+                })
+                .collect(),
+            senses: vec![
+                akasabi::Sense {
+                    glosses: strings(&["raw", "uncooked"]),
+                    pos: strings(&["adjectival nouns"]),
+                    ..akasabi::Sense::default()
+                },
+                akasabi::Sense {
+                    glosses: strings(&["draft beer"]),
+                    ..akasabi::Sense::default()
+                },
+            ],
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn test_read_words() {
+        let words = read_words("生\n\n  カレー \r\ntaberu\n".as_bytes()).unwrap();
+        assert_eq!(words, vec!["生", "カレー", "taberu"]);
+    }
+
+    #[test]
+    fn test_choose() {
+        let results = || {
+            vec![
+                entry(1, &["生物"], &["せいぶつ"]),
+                entry(2, &["生"], &["なま"]),
+                entry(3, &["生"], &["き"]),
+                entry(4, &[], &["カレー"]),
+            ]
+        };
+
+        let lookup = choose("生", results()).unwrap();
+        assert_eq!(lookup.confidence, Confidence::Ambiguous);
+        assert_eq!(lookup.entry.id, Some(2));
+        assert_eq!(lookup.candidates, vec![2, 3]);
+
+        // Kana and romaji are normalized before comparing
+        for input in ["かれー", "kare-", "なま"] {
+            let lookup = choose(input, results()).unwrap();
+            assert_eq!(lookup.confidence, Confidence::Exact, "{input}");
+            assert!(lookup.candidates.is_empty());
+        }
+
+        let lookup = choose("生き物", results()).unwrap();
+        assert_eq!(lookup.confidence, Confidence::Partial);
+        assert_eq!(lookup.entry.id, Some(1));
+
+        assert!(choose("生", Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.xml");
+        std::fs::write(
+            &jmdict_path,
+            "<JMdict><entry><ent_seq>1</ent_seq><r_ele><reb>テレビ</reb></r_ele>\
+             <sense><gloss>television</gloss></sense></entry></JMdict>",
+        )
+        .unwrap();
+        let index_path = dir.path().join("index");
+        Dictionary::index(
+            &index_path,
+            jmdict_path.to_str().unwrap(),
+            &akasabi::indexer::IndexOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let dictionary = Dictionary::open(&index_path).unwrap();
+
+        // Hiragana finds a word only written in katakana
+        for input in ["テレビ", "てれび"] {
+            let lookup = lookup(&dictionary, input).unwrap().unwrap();
+            assert_eq!(lookup.confidence, Confidence::Exact, "{input}");
+            assert_eq!(lookup.entry.id, Some(1));
+        }
+        assert!(lookup(&dictionary, "らじお").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_writer() {
+        let lookup = Lookup {
+            input: "なま".to_string(),
+            confidence: Confidence::Exact,
+            entry: entry(2, &["生", "なま"], &["なま"]),
+            candidates: Vec::new(),
+        };
+        let columns = vec![
+            Column::Input,
+            Column::Kanji,
+            Column::Reading,
+            Column::Glosses,
+            Column::Pos,
+            Column::Id,
+            Column::Confidence,
+        ];
+
+        let mut output = Vec::new();
+        let mut writer = Writer::new(&mut output, Format::Tsv, columns.clone(), 3);
+        writer.write_header().unwrap();
+        writer.write_lookup(&lookup).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "input\tkanji\treading\tglosses\tpos\tent_seq\tconfidence\n\
+             なま\t生; なま\tなま\traw; uncooked; draft beer\tadjectival nouns\t2\texact\n"
+        );
+
+        let mut output = Vec::new();
+        let mut writer = Writer::new(&mut output, Format::Csv, columns, 1);
+        writer.write_lookup(&lookup).unwrap();
+        writer
+            .write_record(&["a,b".to_string(), "say \"hi\"".to_string()])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "なま,生; なま,なま,raw,adjectival nouns,2,exact\r\n\"a,b\",\"say \"\"hi\"\"\"\r\n"
+        );
+    }
+}
@@ -3,17 +3,27 @@ use std::{io::Read, path::PathBuf};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::output;
+
 #[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
 pub(crate) struct Config {
-    #[serde(skip_serializing_if = "Index::is_default")]
+    #[serde(default, skip_serializing_if = "Index::is_default")]
     pub(crate) index: Index,
-    #[serde(skip_serializing_if = "Jmdict::is_default")]
+    #[serde(default, skip_serializing_if = "Jmdict::is_default")]
     pub(crate) jmdict: Jmdict,
+    #[serde(default, skip_serializing_if = "Output::is_default")]
+    pub(crate) output: Output,
+    #[serde(default, skip_serializing_if = "Anki::is_default")]
+    pub(crate) anki: Anki,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
 pub(crate) struct Index {
     pub(crate) path: Option<PathBuf>,
+    /// Threads used for indexing; defaults to the number of CPUs
+    pub(crate) threads: Option<usize>,
+    /// Index writer memory budget, in megabytes
+    pub(crate) writer_memory_mb: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
@@ -22,6 +32,36 @@ pub(crate) struct Jmdict {
     pub(crate) url: Option<String>,
 }
 
+/// Defaults for `search` and `show`'s `--format`, `--columns` and `--tags`.
+#[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub(crate) struct Output {
+    pub(crate) format: Option<output::Format>,
+    pub(crate) columns: Option<Vec<output::Column>>,
+    pub(crate) tags: Option<output::Tags>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub(crate) struct Anki {
+    pub(crate) deck: Option<String>,
+    /// Name of the note type
+    pub(crate) model: Option<String>,
+    /// `AnkiConnect` endpoint, for adding notes to a running Anki
+    pub(crate) connect_url: Option<String>,
+    // NB: Must come last, as TOML tables can't be followed by plain values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) fields: Vec<AnkiField>,
+}
+
+/// A note field, and the template used to fill it from an entry.
+///
+/// Templates may contain `{headword}`, `{kanji}`, `{reading}`, `{senses}`,
+/// `{pos}` and `{id}`.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub(crate) struct AnkiField {
+    pub(crate) name: String,
+    pub(crate) template: String,
+}
+
 impl Config {
     pub(crate) fn from_file<P: Into<PathBuf>>(path: P) -> Result<Self> {
         let path = path.into();
@@ -47,7 +87,19 @@ impl Config {
 
 impl Index {
     pub(crate) fn is_default(&self) -> bool {
-        matches!(self, Index { path: None })
+        self == &Index::default()
+    }
+}
+
+impl Anki {
+    pub(crate) fn is_default(&self) -> bool {
+        self == &Anki::default()
+    }
+}
+
+impl Output {
+    pub(crate) fn is_default(&self) -> bool {
+        self == &Output::default()
     }
 }
 
@@ -85,12 +137,15 @@ mod test {
             config,
             Config {
                 index: Index {
-                    path: Some("/tmp/index".into())
+                    path: Some("/tmp/index".into()),
+                    ..Index::default()
                 },
                 jmdict: Jmdict {
                     path: Some("/tmp/jmdict".into()),
                     url: Some("https://ftp.monash.edu/pub/nihongo/JMdict_e.gz".into())
-                }
+                },
+                output: Output::default(),
+                anki: Anki::default(),
             }
         );
     }
@@ -100,11 +155,14 @@ mod test {
         let config = Config {
             index: Index {
                 path: Some("/tmp/index".into()),
+                ..Index::default()
             },
             jmdict: Jmdict {
                 path: Some("/tmp/jmdict".into()),
                 url: Some("https://ftp.monash.edu/pub/nihongo/JMdict_e.gz".into()),
             },
+            output: Output::default(),
+            anki: Anki::default(),
         };
 
         assert_eq!(
@@ -124,11 +182,14 @@ url = "https://ftp.monash.edu/pub/nihongo/JMdict_e.gz"
         let config = Config {
             index: Index {
                 path: Some("/tmp/index".into()),
+                ..Index::default()
             },
             jmdict: Jmdict {
                 path: None,
                 url: None,
             },
+            output: Output::default(),
+            anki: Anki::default(),
         };
 
         assert_eq!(
@@ -138,4 +199,72 @@ path = "/tmp/index"
 "#
         );
     }
+
+    #[test]
+    fn test_config_output() {
+        let config = Config::from_str(
+            r#"
+            [output]
+            format = "tsv"
+            columns = ["headword", "reading", "glosses"]
+            tags = "abbreviated"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.output,
+            Output {
+                format: Some(output::Format::Tsv),
+                columns: Some(vec![
+                    output::Column::Headword,
+                    output::Column::Reading,
+                    output::Column::Glosses,
+                ]),
+                tags: Some(output::Tags::Abbreviated),
+            }
+        );
+        assert!(config
+            .to_str()
+            .unwrap()
+            .contains("[output]\nformat = \"tsv\""));
+    }
+
+    #[test]
+    fn test_config_anki_fields() {
+        let config = Config::from_str(
+            r#"
+            [anki]
+            deck = "Japanese::Vocabulary"
+
+            [[anki.fields]]
+            name = "Front"
+            template = "{headword}"
+
+            [[anki.fields]]
+            name = "Back"
+            template = "{reading}<br>{senses}"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.anki,
+            Anki {
+                deck: Some("Japanese::Vocabulary".into()),
+                model: None,
+                connect_url: None,
+                fields: vec![
+                    AnkiField {
+                        name: "Front".into(),
+                        template: "{headword}".into()
+                    },
+                    AnkiField {
+                        name: "Back".into(),
+                        template: "{reading}<br>{senses}".into()
+                    },
+                ],
+            }
+        );
+    }
 }
@@ -0,0 +1,970 @@
+//! Opening, (re-)indexing and searching a dictionary index.
+
+use crate::entry::{Entry, Reference};
+use crate::indexer::{self, Changes, IndexMeta, IndexOptions, Progress, VersionMismatch};
+use crate::query::{self, QueryError};
+use crate::{staging, yomitan};
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use tantivy::collector::{Collector, SegmentCollector, TopDocs};
+use tantivy::fastfield::Column;
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::{IndexRecordOption, Schema};
+use tantivy::{
+    DocAddress, DocId, Index, IndexReader, Score, Searcher, SegmentOrdinal, SegmentReader, Term,
+};
+
+/// How many of the best matches for a cross-reference's word are checked
+/// for the right reading and sense.
+const REFERENCE_CANDIDATES: usize = 100;
+
+/// Which field a search looks in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Word,
+    Reading,
+    ReadingRomaji,
+    Meaning,
+}
+
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::Word => "word",
+            Field::Reading => "reading",
+            Field::ReadingRomaji => "reading_romaji",
+            Field::Meaning => "meaning",
+        }
+    }
+}
+
+/// A kind of sense tag that searches can be filtered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Pos,
+    Field,
+    Misc,
+    Dialect,
+}
+
+impl Tag {
+    fn field_name(self) -> &'static str {
+        let [pos, field, misc, dial] = indexer::TAG_FIELDS;
+        match self {
+            Tag::Pos => pos,
+            Tag::Field => field,
+            Tag::Misc => misc,
+            Tag::Dialect => dial,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Tag::Pos => "part of speech",
+            Tag::Field => "field",
+            Tag::Misc => "misc tag",
+            Tag::Dialect => "dialect",
+        }
+    }
+}
+
+/// The order of search results.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    /// Best matches first
+    #[default]
+    Relevance,
+    /// By `ent_seq`, ie. in `JMdict`'s order; imported entries come last
+    Id,
+    /// By the first reading, in kana order
+    Reading,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    tag: Tag,
+    value: String,
+    exclude: bool,
+}
+
+/// How to search; built with `SearchOptions::new().field(...).limit(...)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchOptions {
+    field: Option<Field>,
+    limit: usize,
+    offset: usize,
+    literal: bool,
+    sort: Sort,
+    filters: Vec<Filter>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            field: None,
+            limit: 10,
+            offset: 0,
+            literal: false,
+            sort: Sort::Relevance,
+            filters: Vec::new(),
+        }
+    }
+}
+
+impl SearchOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        SearchOptions::default()
+    }
+
+    /// Only search this field, instead of all of them.
+    #[must_use]
+    pub fn field(mut self, field: Field) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    /// Search for the query as it is, instead of parsing it.
+    #[must_use]
+    pub fn literal(mut self, literal: bool) -> Self {
+        self.literal = literal;
+        self
+    }
+
+    /// Return at most this many results (10 by default).
+    #[must_use]
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Skip this many results first, for paging through them.
+    #[must_use]
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    #[must_use]
+    pub fn sort(mut self, sort: Sort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Only match senses with this tag, given as its `JMdict` entity name
+    /// (eg. "v5r") or its description.
+    #[must_use]
+    pub fn filter(mut self, tag: Tag, value: impl Into<String>) -> Self {
+        self.filters.push(Filter {
+            tag,
+            value: value.into(),
+            exclude: false,
+        });
+        self
+    }
+
+    /// Leave out entries with this tag in any of their senses, like `-` in
+    /// a query. The tag is given like for [`SearchOptions::filter`].
+    #[must_use]
+    pub fn exclude(mut self, tag: Tag, value: impl Into<String>) -> Self {
+        self.filters.push(Filter {
+            tag,
+            value: value.into(),
+            exclude: true,
+        });
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    /// The score of the best-matching sense
+    pub score: f32,
+    pub entry: Entry,
+    /// Positions in `entry.senses` of the senses that matched, best first.
+    /// All of them if the match was on the word or reading.
+    pub senses: Vec<usize>,
+}
+
+/// A dictionary index on disk.
+pub struct Dictionary {
+    path: PathBuf,
+    schema: Schema,
+    index: Index,
+    reader: IndexReader,
+}
+
+impl Dictionary {
+    /// Opens the index at `path`, creating an empty one if there is none.
+    /// Fails with [`VersionMismatch`] if it was built by an incompatible
+    /// version of akasabi; [`Dictionary::index`] replaces it.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let schema = indexer::create_schema();
+        let index = if path.join("meta.json").exists() {
+            let index = Index::open_in_dir(&path).context("Failed to open index")?;
+            IndexMeta::check(&index)?;
+            index
+        } else {
+            create_dir_all(&path).context("Failed to create index directory")?;
+            Index::create_in_dir(&path, schema.clone()).context("Failed to create index")?
+        };
+        indexer::register_tokenizers(&index).context("Failed to load tokenizer dictionary")?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(tantivy::ReloadPolicy::Manual)
+            .try_into()?;
+
+        Ok(Dictionary {
+            path,
+            schema,
+            index,
+            reader,
+        })
+    }
+
+    /// Indexes `JMdict` from `source` (a file, or `-` for stdin) into the
+    /// index at `path`. The index is built in a staging copy and swapped in
+    /// once it's complete, so open dictionaries keep working meanwhile. An
+    /// index built by an incompatible version is replaced, which
+    /// [`Changes::rebuilt`] reports.
+    pub fn index(
+        path: &Path,
+        source: &str,
+        options: &IndexOptions,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<Changes> {
+        let schema = indexer::create_schema();
+        let _lock = staging::lock(path)?;
+
+        // An index built by an incompatible version can only be replaced
+        let mut rebuilt = false;
+        if path.join("meta.json").exists() {
+            let index = Index::open_in_dir(path).context("Failed to open index")?;
+            match IndexMeta::check(&index) {
+                Ok(()) => {}
+                Err(e) if e.is::<VersionMismatch>() => rebuilt = true,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let index = staging::prepare(path, &schema, !rebuilt)?;
+        indexer::register_tokenizers(&index).context("Failed to load tokenizer dictionary")?;
+        let mut changes = indexer::create_index(&schema, source, &index, options, &mut progress)
+            .with_context(|| format!("Failed to index {source}"))?;
+        changes.rebuilt = rebuilt;
+        drop(index);
+
+        if changes.total == 0 {
+            bail!("{source} has no JMdict entries; keeping the current index");
+        }
+        staging::verify(path, &schema, changes.total)?;
+        staging::swap(path)?;
+        Ok(changes)
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[must_use]
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The underlying tantivy index, for anything not covered here.
+    #[must_use]
+    pub fn tantivy_index(&self) -> &Index {
+        &self.index
+    }
+
+    pub fn meta(&self) -> Result<IndexMeta> {
+        IndexMeta::load(&self.index)
+    }
+
+    /// Searches, best matches first. Queries are terms that all have to
+    /// match, which can be limited to a field with `k:` (kanji), `r:`
+    /// (reading), `m:` (meaning), `p:` (part of speech) or `f:` (field),
+    /// excluded with `-`, combined with `a|b` and quoted with `"..."`. An
+    /// empty query matches every entry, to browse them by their tags with
+    /// [`SearchOptions::filter`]. Fails with a [`QueryError`] if the query
+    /// is invalid or a filter's tag is unknown.
+    pub fn search(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchResult>> {
+        let fields = match options.field {
+            Some(field) => vec![field.name()],
+            None => vec![
+                Field::Word.name(),
+                Field::Reading.name(),
+                Field::ReadingRomaji.name(),
+                Field::Meaning.name(),
+            ],
+        };
+        let query = if options.literal {
+            query::literal(query)
+        } else {
+            query::parse(query)?
+        };
+        let entities = if query.has_tags() || !options.filters.is_empty() {
+            self.meta()?.entities
+        } else {
+            BTreeMap::new()
+        };
+        let searcher = self.reader.searcher();
+
+        let query::Queries { matching, excluded } =
+            query.to_tantivy(&self.index, &fields, &entities)?;
+        let mut clauses = vec![(Occur::Must, matching)];
+        // Like excluded terms, excluded tags leave out whole entries
+        let mut exclusions = Vec::from_iter(excluded);
+        for filter in &options.filters {
+            let query = self.filter_query(&searcher, filter, &entities)?;
+            if filter.exclude {
+                exclusions.push(query);
+            } else {
+                clauses.push((Occur::Must, query));
+            }
+        }
+        let query = BooleanQuery::new(clauses);
+
+        let mut entries = matching_entries(&searcher, &query)?;
+        if !exclusions.is_empty() {
+            let excluded = searcher.search(&BooleanQuery::union(exclusions), &EntryCollector)?;
+            entries.retain(|hits| !excluded.contains_key(&hits.hash));
+        }
+        sort_entries(&searcher, &mut entries, options.sort)?;
+        let page = entries
+            .into_iter()
+            .skip(options.offset)
+            .take(options.limit)
+            .map(|hits| {
+                let entry = Entry::from_document(&self.schema, &searcher.doc(hits.address)?)?;
+                Ok((hits, entry))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(page
+            .into_iter()
+            .map(|(hits, entry)| SearchResult {
+                score: hits.score,
+                senses: hits.senses(entry.senses.len()),
+                entry,
+            })
+            .collect())
+    }
+
+    /// The query for a filter. Tags that are neither `JMdict` entities nor
+    /// in the index, eg. misspelt ones, are an error, unless excluded.
+    fn filter_query(
+        &self,
+        searcher: &Searcher,
+        filter: &Filter,
+        entities: &BTreeMap<String, String>,
+    ) -> Result<Box<dyn Query>> {
+        let value = entities.get(&filter.value).unwrap_or(&filter.value);
+        let field = self.schema.get_field(filter.tag.field_name())?;
+        let term = Term::from_field_text(field, value);
+        let known = filter.exclude
+            || entities.values().any(|description| description == value)
+            || searcher.doc_freq(&term)? > 0;
+        if !known {
+            return Err(QueryError {
+                message: format!("unknown {} \"{}\"", filter.tag.label(), filter.value),
+            }
+            .into());
+        }
+        Ok(Box::new(TermQuery::new(term, IndexRecordOption::Basic)))
+    }
+
+    /// Finds the entry a cross-reference points to, preferring `JMdict`
+    /// entries. `None` if there's no such entry, or it lacks the sense.
+    pub fn resolve(&self, reference: &Reference) -> Result<Option<Entry>> {
+        let query = query::literal(&reference.word).to_tantivy(
+            &self.index,
+            &[Field::Word.name(), Field::Reading.name()],
+            &BTreeMap::new(),
+        )?;
+        let searcher = self.reader.searcher();
+
+        let mut found = None;
+        let candidates = matching_entries(&searcher, &*query.matching)?;
+        for hits in candidates.into_iter().take(REFERENCE_CANDIDATES) {
+            let entry = Entry::from_document(&self.schema, &searcher.doc(hits.address)?)?;
+            let has_sense = reference
+                .sense
+                .is_none_or(|sense| (1..=entry.senses.len()).contains(&sense));
+            if !reference.matches(&entry) || !has_sense {
+                continue;
+            }
+            if entry.is_jmdict() {
+                return Ok(Some(entry));
+            }
+            found.get_or_insert(entry);
+        }
+        Ok(found)
+    }
+
+    /// Looks up an entry by its `ent_seq`.
+    pub fn get(&self, id: i64) -> Result<Option<Entry>> {
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(
+            Term::from_field_i64(self.schema.get_field("id").unwrap(), id),
+            IndexRecordOption::Basic,
+        );
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(1))?;
+
+        top_docs
+            .first()
+            .map(|(_, address)| Entry::from_document(&self.schema, &searcher.doc(*address)?))
+            .transpose()
+    }
+
+    /// All entries, including those from imported dictionaries.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let searcher = self.reader.searcher();
+        let addresses = searcher.search(
+            &indexer::entries_query(&self.schema),
+            &tantivy::collector::DocSetCollector,
+        )?;
+        let mut addresses = addresses.into_iter().collect::<Vec<_>>();
+        addresses.sort();
+        addresses
+            .into_iter()
+            .map(|address| Entry::from_document(&self.schema, &searcher.doc(address)?))
+            .collect()
+    }
+
+    /// Adds a Yomitan dictionary, replacing an earlier import with the same
+    /// title, and returns the number of entries imported. Like [`index`],
+    /// this happens in a staging copy that's swapped in once it's complete.
+    /// Only `options.threads` and `options.writer_memory` are used.
+    ///
+    /// [`index`]: Dictionary::index
+    pub fn import_yomitan(
+        &self,
+        dictionary: &yomitan::Dictionary,
+        options: &IndexOptions,
+    ) -> Result<usize> {
+        let _lock = staging::lock(&self.path)?;
+        // Importing doesn't touch JMdict's entries
+        let jmdict_entries = staging::jmdict_entries(&self.index, &self.schema)?;
+
+        let index = staging::prepare(&self.path, &self.schema, true)?;
+        indexer::register_tokenizers(&index).context("Failed to load tokenizer dictionary")?;
+        let count = indexer::import_yomitan(&self.schema, dictionary, &index, options)?;
+        drop(index);
+
+        staging::verify(&self.path, &self.schema, jmdict_entries)?;
+        staging::swap(&self.path)?;
+        self.reader.reload()?;
+        Ok(count)
+    }
+
+    pub fn export_yomitan(&self, title: &str) -> Result<yomitan::Dictionary> {
+        indexer::export_yomitan(&self.schema, &self.index, title)
+    }
+}
+
+/// The documents of one entry that matched a query.
+#[derive(Debug, Clone)]
+struct EntryHits {
+    /// The entry's hash, which all of its documents have
+    hash: u64,
+    /// Score of the best-matching document
+    score: f32,
+    address: DocAddress,
+    /// Positions of the senses that matched, with their scores
+    senses: Vec<(f32, u64)>,
+}
+
+impl EntryHits {
+    /// Orders by relevance: better scores first, and earlier documents first
+    /// when they score the same.
+    fn rank(&self, other: &EntryHits) -> Ordering {
+        other
+            .score
+            .total_cmp(&self.score)
+            .then(self.address.cmp(&other.address))
+    }
+
+    /// Adds more of the entry's documents.
+    fn merge(&mut self, other: EntryHits) {
+        if other.rank(self).is_lt() {
+            (self.score, self.address) = (other.score, other.address);
+        }
+        self.senses.extend(other.senses);
+    }
+
+    /// Positions of the matching senses of an entry with `count` senses,
+    /// best first.
+    fn senses(mut self, count: usize) -> Vec<usize> {
+        // Earlier senses first when they match equally well
+        self.senses
+            .sort_by(|(a_score, a), (b_score, b)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        self.senses
+            .into_iter()
+            .filter_map(|(_, sense)| usize::try_from(sense).ok())
+            .filter(|&sense| sense < count)
+            .collect()
+    }
+}
+
+/// Finds the entries matching `query`, best first. Each sense is its own
+/// document, so documents are grouped by their entry's hash, noting which
+/// senses matched.
+fn matching_entries(searcher: &Searcher, query: &dyn Query) -> Result<Vec<EntryHits>> {
+    let mut entries = searcher
+        .search(query, &EntryCollector)?
+        .into_values()
+        .collect::<Vec<_>>();
+    entries.sort_unstable_by(EntryHits::rank);
+    Ok(entries)
+}
+
+/// Sorts `entries`, which are by relevance already, reading the keys for the
+/// other orders from fast fields so only the page of results is loaded.
+fn sort_entries(searcher: &Searcher, entries: &mut [EntryHits], sort: Sort) -> Result<()> {
+    let segments = searcher.segment_readers();
+    match sort {
+        Sort::Relevance => {}
+        Sort::Id => {
+            let ids = segments
+                .iter()
+                .map(|segment| segment.fast_fields().i64("id"))
+                .collect::<tantivy::Result<Vec<_>>>()?;
+            entries.sort_by_cached_key(|hits| {
+                let address = hits.address;
+                let id = ids[address.segment_ord as usize].first(address.doc_id);
+                (id.is_none(), id)
+            });
+        }
+        Sort::Reading => {
+            let readings = segments
+                .iter()
+                .map(|segment| segment.fast_fields().str("reading_sort"))
+                .collect::<tantivy::Result<Vec<_>>>()?;
+            let mut keys = HashMap::new();
+            for hits in entries.iter() {
+                let address = hits.address;
+                let mut reading = None;
+                if let Some(column) = &readings[address.segment_ord as usize] {
+                    if let Some(ord) = column.term_ords(address.doc_id).next() {
+                        let mut text = String::new();
+                        column.ord_to_str(ord, &mut text)?;
+                        reading = Some(text);
+                    }
+                }
+                keys.insert(hits.hash, (reading.is_none(), reading));
+            }
+            entries.sort_by(|a, b| keys[&a.hash].cmp(&keys[&b.hash]));
+        }
+    }
+    Ok(())
+}
+
+fn add_hits(entries: &mut HashMap<u64, EntryHits>, hits: EntryHits) {
+    match entries.entry(hits.hash) {
+        hash_map::Entry::Occupied(mut entry) => entry.get_mut().merge(hits),
+        hash_map::Entry::Vacant(entry) => {
+            entry.insert(hits);
+        }
+    }
+}
+
+/// Collects the matching documents of each entry, by hash.
+struct EntryCollector;
+
+struct EntrySegmentCollector {
+    segment_ord: SegmentOrdinal,
+    hashes: Column<u64>,
+    senses: Column<u64>,
+    entries: HashMap<u64, EntryHits>,
+}
+
+impl Collector for EntryCollector {
+    type Fruit = HashMap<u64, EntryHits>;
+    type Child = EntrySegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_ord: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<EntrySegmentCollector> {
+        Ok(EntrySegmentCollector {
+            segment_ord,
+            hashes: segment.fast_fields().u64("hash")?,
+            senses: segment.fast_fields().u64("sense")?,
+            entries: HashMap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(
+        &self,
+        segments: Vec<HashMap<u64, EntryHits>>,
+    ) -> tantivy::Result<HashMap<u64, EntryHits>> {
+        let mut merged = HashMap::new();
+        for hits in segments.into_iter().flat_map(HashMap::into_values) {
+            add_hits(&mut merged, hits);
+        }
+        Ok(merged)
+    }
+}
+
+impl SegmentCollector for EntrySegmentCollector {
+    type Fruit = HashMap<u64, EntryHits>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let (Some(hash), Some(sense)) = (self.hashes.first(doc), self.senses.first(doc)) else {
+            return;
+        };
+        let hits = EntryHits {
+            hash,
+            score,
+            address: DocAddress::new(self.segment_ord, doc),
+            senses: vec![(score, sense)],
+        };
+        add_hits(&mut self.entries, hits);
+    }
+
+    fn harvest(self) -> HashMap<u64, EntryHits> {
+        self.entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wana_kana::ConvertJapanese;
+
+    #[test]
+    fn test_dictionary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let changes = Dictionary::index(
+            &path,
+            "testdata/JMdict_e_test.gz",
+            &IndexOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(changes.total, 101);
+
+        let dictionary = Dictionary::open(&path).unwrap();
+        let results = dictionary.search("赤錆", &SearchOptions::new()).unwrap();
+        let entry = &results[0].entry;
+        assert_eq!(entry.id, Some(1_829_380));
+        assert!(entry.is_jmdict());
+        assert_eq!(entry.headword(), "赤錆");
+        assert_eq!(entry.readings[0].text, "あかさび");
+        assert_eq!(entry.senses[0].glosses, vec!["rust"]);
+        assert_eq!(entry.senses[0].pos, vec!["noun (common) (futsuumeishi)"]);
+
+        assert_eq!(results[0].senses, vec![0]);
+
+        // Meaning matches point to the sense that matched
+        let options = SearchOptions::new().field(Field::Meaning);
+        let results = dictionary.search("fault", &options).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].entry.headword(), "論う");
+        assert_eq!(results[0].entry.senses.len(), 2);
+        assert_eq!(results[0].senses, vec![1]);
+        // Matching word and meaning in one sense
+        let results = dictionary
+            .search("論う criticize", &SearchOptions::new())
+            .unwrap();
+        assert_eq!(results[0].senses, vec![1]);
+        // Several senses of an entry match, but it's listed once
+        let results = dictionary.search("restless", &options).unwrap();
+        assert_eq!(results[0].entry.headword(), "うろうろ");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].senses.len(), 2);
+        let results = dictionary
+            .search("restless", &options.clone().limit(1))
+            .unwrap();
+        assert_eq!(results[0].senses.len(), 2);
+
+        let options = SearchOptions::new().field(Field::Reading).limit(1);
+        assert_eq!(dictionary.search("rust", &options).unwrap(), Vec::new());
+        let options = SearchOptions::new().field(Field::Meaning).limit(1);
+        assert_eq!(dictionary.search("rust", &options).unwrap().len(), 1);
+
+        // Query language
+        let search = |query| {
+            let results = dictionary.search(query, &SearchOptions::new()).unwrap();
+            results
+                .into_iter()
+                .map(|r| r.entry.headword().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search("m:rust k:赤錆"), vec!["赤錆"]);
+        assert!(!search("m:rust -k:赤錆").contains(&"赤錆".to_string()));
+        assert_eq!(search("k:赤錆|k:論う").len(), 2);
+        assert_eq!(search("r:akasabi"), vec!["赤錆"]);
+        // Entity names work as well as their descriptions. Meaning and tags
+        // have to match in the same sense.
+        assert_eq!(search("p:adv-to m:aimlessly"), vec!["うろうろ"]);
+        assert_eq!(search("p:\"adverb taking\" m:aimlessly"), vec!["うろうろ"]);
+        assert_eq!(search("m:purpose -p:adv-to"), Vec::<String>::new());
+        // Excluded terms leave out the whole entry, even if they're in
+        // another sense than the one that matched
+        assert!(search("m:discuss").contains(&"論う".to_string()));
+        assert!(!search("m:discuss -m:fault").contains(&"論う".to_string()));
+        assert_eq!(search("m:restless -p:adv-to"), Vec::<String>::new());
+        let error = dictionary
+            .search("x:rust", &SearchOptions::new())
+            .unwrap_err();
+        assert!(error.is::<QueryError>());
+        let options = SearchOptions::new().literal(true);
+        assert_eq!(dictionary.search("m:rust", &options).unwrap(), Vec::new());
+
+        assert_eq!(dictionary.get(1_829_380).unwrap().as_ref(), Some(entry));
+        assert_eq!(dictionary.get(1).unwrap(), None);
+
+        // Cross-references are parsed, and resolve to the right entry and sense
+        let irasshai = dictionary.get(1_000_920).unwrap().unwrap();
+        let reference = &irasshai.senses[0].xref[0];
+        assert_eq!(reference.to_string(), "いらっしゃる (1)");
+        let target = dictionary.resolve(reference).unwrap().unwrap();
+        assert_eq!(target.headword(), "いらっしゃる");
+        let resolve = |text| dictionary.resolve(&Reference::parse(text)).unwrap();
+        assert_eq!(resolve("いらっしゃる・9"), None);
+        assert_eq!(resolve("丸・まる・1"), None);
+        assert_eq!(resolve("赤錆・あかさび").unwrap().id, Some(1_829_380));
+        assert_eq!(resolve("赤錆・さび"), None);
+        assert_eq!(dictionary.entries().unwrap().len(), 101);
+    }
+
+    #[test]
+    fn test_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.xml");
+        std::fs::write(
+            &jmdict_path,
+            "<JMdict><entry><ent_seq>1</ent_seq><r_ele><reb>さび</reb></r_ele>\
+             <sense><pos>noun</pos><misc>rare; archaic</misc><gloss>rust; corrosion</gloss>\
+             <gloss>patina</gloss></sense><sense><gloss>tarnish</gloss></sense></entry></JMdict>",
+        )
+        .unwrap();
+        let jmdict_path = jmdict_path.to_str().unwrap();
+        let path = dir.path().join("index");
+        Dictionary::index(&path, jmdict_path, &IndexOptions::default(), |_| {}).unwrap();
+
+        // Entries read back from the index are the ones in the file, so
+        // `akasabi diff` against an index finds no spurious changes
+        let dictionary = Dictionary::open(&path).unwrap();
+        let entries = dictionary.entries().unwrap();
+        let (read, _) = indexer::read_entries(jmdict_path).unwrap();
+        assert_eq!(entries, read.into_values().collect::<Vec<_>>());
+        assert_eq!(
+            entries[0].senses[0].glosses,
+            vec!["rust; corrosion", "patina"]
+        );
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.xml");
+        std::fs::write(
+            &jmdict_path,
+            "<JMdict><entry><ent_seq>1</ent_seq><r_ele><reb>さび</reb></r_ele>\
+             <sense><gloss>rust</gloss></sense></entry></JMdict>",
+        )
+        .unwrap();
+        let jmdict_path = jmdict_path.to_str().unwrap();
+        let path = dir.path().join("index");
+
+        // An index from before versioning
+        let schema = indexer::create_schema();
+        std::fs::create_dir(&path).unwrap();
+        let index = Index::create_in_dir(&path, schema.clone()).unwrap();
+        let mut writer = index.writer(15_000_000).unwrap();
+        let mut document = tantivy::Document::default();
+        document.add_u64(schema.get_field("sense").unwrap(), 0);
+        writer.add_document(document).unwrap();
+        writer.commit().unwrap();
+        drop((writer, index));
+
+        let changes =
+            Dictionary::index(&path, jmdict_path, &IndexOptions::default(), |_| {}).unwrap();
+        assert!(changes.rebuilt);
+        assert_eq!(changes.total, 1);
+        let changes =
+            Dictionary::index(&path, jmdict_path, &IndexOptions::default(), |_| {}).unwrap();
+        assert!(!changes.rebuilt);
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        Dictionary::index(
+            &path,
+            "testdata/JMdict_e_test.gz",
+            &IndexOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let dictionary = Dictionary::open(&path).unwrap();
+        let searcher = dictionary.reader.searcher();
+
+        // Imports are swapped in like indexing, leaving open searches alone
+        let yomitan =
+            yomitan::Dictionary::from_reader(std::io::Cursor::new(yomitan::test::test_archive()))
+                .unwrap();
+        let count = dictionary
+            .import_yomitan(&yomitan, &IndexOptions::default())
+            .unwrap();
+        assert_eq!(count, 6);
+        assert!(!staging::staging_path(&path).exists());
+        let options = SearchOptions::new().field(Field::Word);
+        let results = dictionary.search("錆びる", &options).unwrap();
+        assert_eq!(results[0].entry.source, "Test Dictionary");
+        assert_eq!(dictionary.entries().unwrap().len(), 107);
+        assert_eq!(searcher.num_docs(), 151);
+    }
+
+    #[test]
+    fn test_browse() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        Dictionary::index(
+            &path,
+            "testdata/JMdict_e_test.gz",
+            &IndexOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let dictionary = Dictionary::open(&path).unwrap();
+
+        let browse = |options: SearchOptions| {
+            let results = dictionary.search("", &options.sort(Sort::Id)).unwrap();
+            results
+                .into_iter()
+                .map(|r| r.entry.id.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let v5r = SearchOptions::new().filter(Tag::Pos, "v5r");
+        assert_eq!(browse(v5r.clone()), vec![1_000_900, 1_001_180]);
+        assert_eq!(browse(v5r.exclude(Tag::Pos, "vt")), vec![1_001_180]);
+        let ksb = SearchOptions::new().filter(Tag::Dialect, "ksb");
+        let all = browse(ksb.clone());
+        assert_eq!(all.len(), 4);
+        assert_eq!(browse(ksb.clone().limit(2).offset(2)), all[2..]);
+        // Tags can also be given by their description
+        assert_eq!(
+            browse(SearchOptions::new().filter(Tag::Dialect, "Kansai-ben")),
+            all
+        );
+        assert_eq!(
+            browse(SearchOptions::new().limit(3)),
+            vec![1_000_000, 1_000_010, 1_000_020]
+        );
+        let options = SearchOptions::new().sort(Sort::Reading).limit(200);
+        let readings = dictionary.search("", &options).unwrap();
+        let readings = readings
+            .iter()
+            .map(|r| r.entry.readings[0].text.to_hiragana())
+            .collect::<Vec<_>>();
+        assert_eq!(readings.len(), 101);
+        assert!(readings.is_sorted());
+        let page = dictionary
+            .search("", &options.offset(50).limit(10))
+            .unwrap();
+        let page = page.iter().map(|r| r.entry.readings[0].text.to_hiragana());
+        assert_eq!(page.collect::<Vec<_>>(), readings[50..60]);
+        // Filters apply to the senses that match the query
+        let options = SearchOptions::new().filter(Tag::Misc, "on-mim");
+        let results = dictionary.search("m:restless", &options).unwrap();
+        assert_eq!(results[0].entry.headword(), "うろうろ");
+        // ...while excluded ones leave out the entry if any sense has them
+        let options = SearchOptions::new().exclude(Tag::Pos, "adv-to");
+        assert_eq!(dictionary.search("m:loiter", &options).unwrap(), Vec::new());
+        // Known tags that no sense has just don't match, but unknown ones
+        // are an error unless they're excluded
+        let options = SearchOptions::new().filter(Tag::Field, "comp");
+        assert_eq!(dictionary.search("", &options).unwrap(), Vec::new());
+        let options = SearchOptions::new().filter(Tag::Misc, "on-mimi");
+        let error = dictionary.search("", &options).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid query: unknown misc tag \"on-mimi\""
+        );
+        let options = SearchOptions::new()
+            .limit(200)
+            .exclude(Tag::Misc, "on-mimi");
+        assert_eq!(dictionary.search("", &options).unwrap().len(), 101);
+    }
+
+    /// Measures the size of an index of copies of the test fixture, and how
+    /// fast entries are read back from it. Run with
+    /// `cargo test --release bench_retrieval -- --ignored --nocapture`.
+    ///
+    /// On 200 copies (20200 entries, release build, best of three runs):
+    ///
+    /// | layout                               | size    | per entry | 100 searches |
+    /// | ------------------------------------ | ------- | --------- | ------------ |
+    /// | aligned stored fields                | 3512 kB | 4.0 µs    | 22 ms        |
+    /// | one bincode `Entry` blob             | 3170 kB | 2.2 µs    | 14 ms        |
+    /// | a document per sense, fast fields    | 4720 kB | 2.4 µs    | 18 ms        |
+    ///
+    /// A document per sense stores the blob in each of them, which is where
+    /// most of the extra size comes from.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_retrieval() {
+        const COPIES: i64 = 200;
+
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.gz");
+        indexer::test::write_fixture_copies(&jmdict_path, COPIES);
+        let path = dir.path().join("index");
+        Dictionary::index(
+            &path,
+            jmdict_path.to_str().unwrap(),
+            &IndexOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+
+        let bytes: u64 = std::fs::read_dir(&path)
+            .unwrap()
+            .map(|file| file.unwrap().metadata().unwrap().len())
+            .sum();
+        println!("index size: {} kB", bytes / 1000);
+
+        // Best of several rounds, as single runs vary a lot
+        let dictionary = Dictionary::open(&path).unwrap();
+        let best = |run: &dyn Fn()| {
+            (0..5)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    run();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let count = dictionary.entries().unwrap().len();
+        let elapsed = best(&|| assert_eq!(dictionary.entries().unwrap().len(), count));
+        println!(
+            "read {count} entries in {elapsed:.2?} ({:.2?} per entry)",
+            elapsed / u32::try_from(count).unwrap()
+        );
+
+        let options = SearchOptions::new().field(Field::Meaning).limit(100);
+        let elapsed = best(&|| {
+            for _ in 0..100 {
+                assert_eq!(dictionary.search("rust", &options).unwrap().len(), 100);
+            }
+        });
+        println!("100 searches in {elapsed:.2?}");
+    }
+}
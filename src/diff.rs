@@ -0,0 +1,455 @@
+//! Differences between two `JMdict` releases (or index generations), keyed
+//! by `ent_seq`.
+
+use akasabi::indexer::Provenance;
+use akasabi::{Entry, Reference, Sense};
+use anyhow::Result;
+use itertools::{EitherOrBoth, Itertools};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Text,
+    Json,
+    Html,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Diff {
+    /// Where the two releases came from, if known; only in the JSON output
+    pub(crate) old_jmdict: Option<Provenance>,
+    pub(crate) new_jmdict: Option<Provenance>,
+    pub(crate) added: Vec<Entry>,
+    pub(crate) removed: Vec<Entry>,
+    pub(crate) modified: Vec<Modified>,
+}
+
+/// An entry that's in both releases, but differs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Modified {
+    pub(crate) ent_seq: i64,
+    /// Headword in the new release, for display
+    pub(crate) headword: String,
+    #[serde(skip_serializing_if = "Changes::is_empty")]
+    pub(crate) kanji: Changes,
+    #[serde(skip_serializing_if = "Changes::is_empty")]
+    pub(crate) readings: Changes,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) senses: Vec<SenseDiff>,
+}
+
+/// Values only in the old and only in the new list.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Changes {
+    pub(crate) removed: Vec<String>,
+    pub(crate) added: Vec<String>,
+}
+
+impl Changes {
+    fn new(old: &[String], new: &[String]) -> Self {
+        Changes {
+            removed: old.iter().filter(|v| !new.contains(v)).cloned().collect(),
+            added: new.iter().filter(|v| !old.contains(v)).cloned().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Senses are compared by position; `sense` counts from 1.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub(crate) enum SenseDiff {
+    Added {
+        sense: usize,
+        #[serde(flatten)]
+        value: Sense,
+    },
+    Removed {
+        sense: usize,
+        #[serde(flatten)]
+        value: Sense,
+    },
+    Changed {
+        sense: usize,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        glosses: Changes,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        pos: Changes,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        field: Changes,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        misc: Changes,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        dial: Changes,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        xref: Changes,
+        #[serde(skip_serializing_if = "Changes::is_empty")]
+        ant: Changes,
+    },
+}
+
+impl SenseDiff {
+    /// Label and changes of each part of a changed sense that differs.
+    fn parts(&self) -> Vec<(&'static str, &Changes)> {
+        let SenseDiff::Changed {
+            glosses,
+            pos,
+            field,
+            misc,
+            dial,
+            xref,
+            ant,
+            ..
+        } = self
+        else {
+            return Vec::new();
+        };
+        [
+            ("glosses", glosses),
+            ("pos", pos),
+            ("field", field),
+            ("misc", misc),
+            ("dialect", dial),
+            ("see also", xref),
+            ("antonyms", ant),
+        ]
+        .into_iter()
+        .filter(|(_, changes)| !changes.is_empty())
+        .collect()
+    }
+}
+
+fn references(references: &[Reference]) -> Vec<String> {
+    references.iter().map(ToString::to_string).collect()
+}
+
+fn modified(ent_seq: i64, old: &Entry, new: &Entry) -> Modified {
+    let texts = |entry: &Entry| {
+        (
+            entry.kanji.iter().map(|k| k.text.clone()).collect_vec(),
+            entry.readings.iter().map(|r| r.text.clone()).collect_vec(),
+        )
+    };
+    let ((old_kanji, old_readings), (new_kanji, new_readings)) = (texts(old), texts(new));
+    let senses = old
+        .senses
+        .iter()
+        .zip_longest(&new.senses)
+        .enumerate()
+        .filter_map(|(i, senses)| {
+            let sense = i + 1;
+            match senses {
+                EitherOrBoth::Both(old, new) if old == new => None,
+                EitherOrBoth::Both(old, new) => Some(SenseDiff::Changed {
+                    sense,
+                    glosses: Changes::new(&old.glosses, &new.glosses),
+                    pos: Changes::new(&old.pos, &new.pos),
+                    field: Changes::new(&old.field, &new.field),
+                    misc: Changes::new(&old.misc, &new.misc),
+                    dial: Changes::new(&old.dial, &new.dial),
+                    xref: Changes::new(&references(&old.xref), &references(&new.xref)),
+                    ant: Changes::new(&references(&old.ant), &references(&new.ant)),
+                }),
+                EitherOrBoth::Left(old) => Some(SenseDiff::Removed {
+                    sense,
+                    value: old.clone(),
+                }),
+                EitherOrBoth::Right(new) => Some(SenseDiff::Added {
+                    sense,
+                    value: new.clone(),
+                }),
+            }
+        })
+        .collect();
+    Modified {
+        ent_seq,
+        headword: new.headword().to_string(),
+        kanji: Changes::new(&old_kanji, &new_kanji),
+        readings: Changes::new(&old_readings, &new_readings),
+        senses,
+    }
+}
+
+pub(crate) fn diff(old: &BTreeMap<i64, Entry>, new: &BTreeMap<i64, Entry>) -> Diff {
+    let mut diff = Diff::default();
+    for entries in old.iter().merge_join_by(new, |(a, _), (b, _)| a.cmp(b)) {
+        match entries {
+            EitherOrBoth::Both((_, old), (_, new)) if old == new => {}
+            EitherOrBoth::Both((&ent_seq, old), (_, new)) => {
+                diff.modified.push(modified(ent_seq, old, new));
+            }
+            EitherOrBoth::Left((_, old)) => diff.removed.push(old.clone()),
+            EitherOrBoth::Right((_, new)) => diff.added.push(new.clone()),
+        }
+    }
+    diff
+}
+
+impl Diff {
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "{} added, {} removed, {} modified",
+            self.added.len(),
+            self.removed.len(),
+            self.modified.len()
+        )
+    }
+
+    pub(crate) fn write(&self, mut out: impl Write, format: Format) -> Result<()> {
+        match format {
+            Format::Text => self.write_text(&mut out)?,
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut out, self)?;
+                writeln!(out)?;
+            }
+            Format::Html => self.write_html(&mut out)?,
+        }
+        Ok(())
+    }
+
+    fn write_text(&self, out: &mut impl Write) -> Result<()> {
+        let line = |values: &[String]| values.join("; ");
+        for entry in &self.added {
+            writeln!(out, "+ {} {}", id(entry), entry.headword())?;
+        }
+        for entry in &self.removed {
+            writeln!(out, "- {} {}", id(entry), entry.headword())?;
+        }
+        for entry in &self.modified {
+            writeln!(out, "~ {} {}", entry.ent_seq, entry.headword)?;
+            for (label, changes) in [("kanji", &entry.kanji), ("readings", &entry.readings)] {
+                if !changes.is_empty() {
+                    writeln!(out, "    {label}: {}", text_changes(changes))?;
+                }
+            }
+            for diff in &entry.senses {
+                match diff {
+                    SenseDiff::Added { sense, value } => {
+                        writeln!(out, "    + sense {sense}: {}", line(&value.glosses))?;
+                    }
+                    SenseDiff::Removed { sense, value } => {
+                        writeln!(out, "    - sense {sense}: {}", line(&value.glosses))?;
+                    }
+                    SenseDiff::Changed { sense, .. } => {
+                        for (label, changes) in diff.parts() {
+                            writeln!(out, "    sense {sense} {label}: {}", text_changes(changes))?;
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(out, "{}", self.summary())?;
+        Ok(())
+    }
+
+    fn write_html(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(
+            out,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>JMdict changes</title>\
+             <style>ins {{ color: green; }} del {{ color: red; }}</style></head><body>"
+        )?;
+        writeln!(out, "<p>{}</p>", self.summary())?;
+
+        for (title, entries) in [("Added", &self.added), ("Removed", &self.removed)] {
+            if entries.is_empty() {
+                continue;
+            }
+            writeln!(out, "<h2>{title}</h2>\n<ul>")?;
+            for entry in entries {
+                writeln!(
+                    out,
+                    "<li>{} <b>{}</b> {}</li>",
+                    id(entry),
+                    escape(entry.headword()),
+                    escape(
+                        &entry
+                            .senses
+                            .iter()
+                            .map(|sense| sense.glosses.join("; "))
+                            .join(" / ")
+                    )
+                )?;
+            }
+            writeln!(out, "</ul>")?;
+        }
+
+        if !self.modified.is_empty() {
+            writeln!(out, "<h2>Modified</h2>\n<ul>")?;
+            for entry in &self.modified {
+                writeln!(
+                    out,
+                    "<li>{} <b>{}</b><ul>",
+                    entry.ent_seq,
+                    escape(&entry.headword)
+                )?;
+                for (label, changes) in [("kanji", &entry.kanji), ("readings", &entry.readings)] {
+                    if !changes.is_empty() {
+                        writeln!(out, "<li>{label}: {}</li>", html_changes(changes))?;
+                    }
+                }
+                for diff in &entry.senses {
+                    match diff {
+                        SenseDiff::Added { sense, value } => writeln!(
+                            out,
+                            "<li>sense {sense}: <ins>{}</ins></li>",
+                            escape(&value.glosses.join("; "))
+                        )?,
+                        SenseDiff::Removed { sense, value } => writeln!(
+                            out,
+                            "<li>sense {sense}: <del>{}</del></li>",
+                            escape(&value.glosses.join("; "))
+                        )?,
+                        SenseDiff::Changed { sense, .. } => {
+                            for (label, changes) in diff.parts() {
+                                writeln!(
+                                    out,
+                                    "<li>sense {sense} {label}: {}</li>",
+                                    html_changes(changes)
+                                )?;
+                            }
+                        }
+                    }
+                }
+                writeln!(out, "</ul></li>")?;
+            }
+            writeln!(out, "</ul>")?;
+        }
+        writeln!(out, "</body></html>")?;
+        Ok(())
+    }
+}
+
+fn id(entry: &Entry) -> i64 {
+    entry.id.unwrap_or_default()
+}
+
+fn text_changes(changes: &Changes) -> String {
+    changes
+        .removed
+        .iter()
+        .map(|value| format!("-{value}"))
+        .chain(changes.added.iter().map(|value| format!("+{value}")))
+        .join(" ")
+}
+
+fn html_changes(changes: &Changes) -> String {
+    changes
+        .removed
+        .iter()
+        .map(|value| format!("<del>{}</del>", escape(value)))
+        .chain(
+            changes
+                .added
+                .iter()
+                .map(|value| format!("<ins>{}</ins>", escape(value))),
+        )
+        .join(" ")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use akasabi::{KanjiForm, Reading};
+
+    fn entry(ent_seq: i64, kanji: &[&str], readings: &[&str], senses: &[&[&str]]) -> Entry {
+        let strings = |values: &[&str]| values.iter().map(ToString::to_string).collect_vec();
+        Entry {
+            id: Some(ent_seq),
+            kanji: kanji
+                .iter()
+                .map(|text| KanjiForm {
+                    text: text.to_string(),
+                })
+                .collect(),
+            readings: readings
+                .iter()
+                .map(|text| Reading {
+                    text: text.to_string(),
+                })
+                .collect(),
+            senses: senses
+                .iter()
+                .map(|glosses| Sense {
+                    glosses: strings(glosses),
+                    pos: vec!["noun".to_string()],
+                    ..Sense::default()
+                })
+                .collect(),
+            ..Entry::default()
+        }
+    }
+
+    fn by_id(entries: Vec<Entry>) -> BTreeMap<i64, Entry> {
+        entries.into_iter().map(|e| (e.id.unwrap(), e)).collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = by_id(vec![
+            entry(1, &["赤錆"], &["あかさび"], &[&["rust"]]),
+            entry(2, &[], &["なま"], &[&["raw"]]),
+            entry(3, &["生"], &["き"], &[&["pure"], &["undiluted"]]),
+        ]);
+        let new = by_id(vec![
+            entry(1, &["赤錆", "赤さび"], &["あかさび"], &[&["rust"]]),
+            entry(
+                3,
+                &["生"],
+                &["き"],
+                &[&["pure", "genuine"], &["undiluted"], &["draft"]],
+            ),
+            entry(4, &[], &["カレー"], &[&["curry"]]),
+        ]);
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.added, vec![new[&4].clone()]);
+        assert_eq!(changes.removed, vec![old[&2].clone()]);
+        assert_eq!(changes.modified.len(), 2);
+        assert_eq!(
+            changes.modified[0].kanji,
+            Changes {
+                removed: Vec::new(),
+                added: vec!["赤さび".to_string()]
+            }
+        );
+        assert!(changes.modified[0].senses.is_empty());
+        assert_eq!(changes.modified[1].senses.len(), 2);
+
+        let mut text = Vec::new();
+        changes.write(&mut text, Format::Text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "+ 4 カレー\n\
+             - 2 なま\n\
+             ~ 1 赤錆\n    kanji: +赤さび\n\
+             ~ 3 生\n    sense 1 glosses: +genuine\n    + sense 3: draft\n\
+             1 added, 1 removed, 2 modified\n"
+        );
+
+        let mut json = Vec::new();
+        changes.write(&mut json, Format::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["modified"][1]["senses"][1]["change"], "added");
+        assert_eq!(json["modified"][1]["senses"][1]["glosses"][0], "draft");
+
+        let mut html = Vec::new();
+        changes.write(&mut html, Format::Html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("<li>kanji: <ins>赤さび</ins></li>"));
+        assert!(html.contains("<li>sense 1 glosses: <ins>genuine</ins></li>"));
+
+        assert_eq!(diff(&new, &new), Diff::default());
+    }
+}
@@ -0,0 +1,194 @@
+//! The dictionary's entry model, shared by parsing, indexing and search results.
+
+use crate::indexer::JMDICT_SOURCE;
+use anyhow::{Context, Result};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tantivy::schema::{Schema, Value};
+use tantivy::Document;
+use wana_kana::IsJapaneseStr;
+
+/// A dictionary entry: a word with its written forms, readings and senses.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// `ent_seq`; only set for `JMdict` entries
+    pub id: Option<i64>,
+    /// `JMdict`, or the title of the imported dictionary the entry came from
+    pub source: String,
+    pub kanji: Vec<KanjiForm>,
+    pub readings: Vec<Reading>,
+    pub senses: Vec<Sense>,
+    /// From imported frequency lists
+    pub frequency: Option<i64>,
+    /// From imported pitch accent dictionaries
+    pub pitch: Vec<String>,
+}
+
+/// A written form of a word, in kanji (`<keb>` in `JMdict`).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KanjiForm {
+    pub text: String,
+}
+
+/// A reading of a word, in kana (`<reb>` in `JMdict`).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reading {
+    pub text: String,
+}
+
+/// One meaning of a word. Part of speech, field, misc and dialect tags are
+/// stored as their descriptions, eg. "noun (common) (futsuumeishi)".
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sense {
+    pub glosses: Vec<String>,
+    pub pos: Vec<String>,
+    pub field: Vec<String>,
+    pub misc: Vec<String>,
+    /// Dialects (`<dial>`), eg. "Kansai-ben"
+    pub dial: Vec<String>,
+    /// Related entries (`<xref>`)
+    pub xref: Vec<Reference>,
+    /// Antonyms (`<ant>`)
+    pub ant: Vec<Reference>,
+}
+
+/// A reference to another entry, or one of its senses, as written in
+/// `JMdict`: "言う・いう・2" is the second sense of the entry written 言う
+/// and read いう.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Reference {
+    /// A written form, or a reading if that's all that is given
+    pub word: String,
+    pub reading: Option<String>,
+    /// Counts from 1
+    pub sense: Option<usize>,
+}
+
+impl Reference {
+    /// Parses `keb・reb・sense`, where the reading and sense number are optional.
+    #[must_use]
+    pub fn parse(text: &str) -> Self {
+        let mut parts = text.split('・').collect::<Vec<_>>();
+        let sense = match parts.last().map(|last| last.parse()) {
+            Some(Ok(sense)) if parts.len() > 1 => {
+                parts.pop();
+                Some(sense)
+            }
+            _ => None,
+        };
+        match parts.as_slice() {
+            // Only words with kanji are followed by a reading, so "ア・ラ" is one word
+            [word, reading] if !word.is_kana() && reading.is_kana() => Reference {
+                word: (*word).to_string(),
+                reading: Some((*reading).to_string()),
+                sense,
+            },
+            // Anything else is a single word, which may contain "・" itself
+            _ => Reference {
+                word: parts.join("・"),
+                reading: None,
+                sense,
+            },
+        }
+    }
+
+    /// Whether this refers to `entry` (regardless of the sense number).
+    #[must_use]
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let is_reading = |text: &str| entry.readings.iter().any(|r| r.text == text);
+        let word = entry.kanji.iter().any(|k| k.text == self.word) || is_reading(&self.word);
+        word && self.reading.as_deref().is_none_or(is_reading)
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.word)?;
+        if let Some(reading) = &self.reading {
+            write!(f, "・{reading}")?;
+        }
+        if let Some(sense) = self.sense {
+            write!(f, " ({sense})")?;
+        }
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// The main written form, or the main reading for kana-only words.
+    #[must_use]
+    pub fn headword(&self) -> &str {
+        self.kanji
+            .first()
+            .map(|k| k.text.as_str())
+            .or(self.readings.first().map(|r| r.text.as_str()))
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn is_jmdict(&self) -> bool {
+        self.source == JMDICT_SOURCE
+    }
+
+    /// Encodes the entry for the index's stored `entry` field. Variable-length
+    /// integers keep the many short strings' length prefixes small.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        bincode::DefaultOptions::new()
+            .serialize(self)
+            .expect("entries can always be encoded")
+    }
+
+    /// Decodes an entry from a stored document.
+    pub(crate) fn from_document(schema: &Schema, document: &Document) -> Result<Self> {
+        let bytes = document
+            .get_first(schema.get_field("entry").unwrap())
+            .and_then(Value::as_bytes)
+            .context("Document has no stored entry")?;
+        bincode::DefaultOptions::new()
+            .deserialize(bytes)
+            .context("Failed to decode stored entry")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reference() {
+        let reference = Reference::parse("言う・いう・2");
+        assert_eq!(
+            reference,
+            Reference {
+                word: "言う".into(),
+                reading: Some("いう".into()),
+                sense: Some(2),
+            }
+        );
+        assert_eq!(reference.to_string(), "言う・いう (2)");
+
+        assert_eq!(Reference::parse("〇〇・1").to_string(), "〇〇 (1)");
+        assert_eq!(
+            Reference::parse("丸・まる").reading.as_deref(),
+            Some("まる")
+        );
+        assert_eq!(Reference::parse("二重丸").to_string(), "二重丸");
+        assert_eq!(Reference::parse("ア・ラ・カルト").word, "ア・ラ・カルト");
+        assert_eq!(Reference::parse("ア・ラ").to_string(), "ア・ラ");
+        assert_eq!(Reference::parse("ア・ラ・2").to_string(), "ア・ラ (2)");
+        assert_eq!(Reference::parse("Ｘ・線").reading, None);
+
+        let entry = Entry {
+            kanji: vec![KanjiForm { text: "丸".into() }],
+            readings: vec![Reading {
+                text: "まる".into(),
+            }],
+            ..Entry::default()
+        };
+        assert!(Reference::parse("丸・まる・1").matches(&entry));
+        assert!(Reference::parse("まる").matches(&entry));
+        assert!(!Reference::parse("丸・えん").matches(&entry));
+        assert!(!Reference::parse("円").matches(&entry));
+    }
+}
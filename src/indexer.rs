@@ -1,187 +1,1329 @@
-#![deny(clippy::all)]
-#![deny(clippy::pedantic)]
-
-use anyhow::Result;
-use flate2::read::GzDecoder;
+use crate::entry::{Entry, KanjiForm, Reading, Reference, Sense};
+use crate::yomitan;
+use anyhow::{Context, Result};
+use flate2::bufread::MultiGzDecoder;
+use itertools::Itertools;
+use lindera_core::mode::Mode;
+use lindera_dictionary::{load_dictionary_from_config, DictionaryConfig, DictionaryKind};
+use lindera_tantivy::tokenizer::LinderaTokenizer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, Read, Write};
+use std::io::{BufReader, Cursor, Read, Write};
 use std::path::Path;
-use tantivy::schema::{Schema, TextFieldIndexing, TextOptions, INDEXED, STORED, TEXT};
-use tantivy::Index;
-use wana_kana::ConvertJapanese;
-use xml::reader::XmlEvent;
+use std::sync::{mpsc, Arc, Mutex};
+use tantivy::query::TermQuery;
+use tantivy::schema::{
+    IndexRecordOption, Schema, TextFieldIndexing, TextOptions, FAST, INDEXED, STORED, STRING, TEXT,
+};
+use tantivy::{Index, IndexWriter, Term};
+use wana_kana::{ConvertJapanese, IsJapaneseStr};
+use xml::common::{Position, TextPosition};
+use xml::reader::{ParserConfig, XmlEvent};
 use xml::EventReader;
-use yansi::Paint;
 
+#[must_use]
 pub fn create_schema() -> Schema {
     let mut builder = Schema::builder();
 
-    let jp_options = TextOptions::default()
-        .set_indexing_options(TextFieldIndexing::default().set_tokenizer("ja_JP"))
-        .set_stored();
+    // Positions are needed for phrase queries, which lindera produces for most multi-kana input
+    let jp_options = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer("ja_JP")
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
 
-    // ent_seq
-    builder.add_i64_field("id", INDEXED | STORED);
+    // ent_seq; only set for JMdict documents, so it's safe to delete by
+    builder.add_i64_field("id", INDEXED | FAST);
+    // hash of the entry, to find changed entries when re-indexing; also groups
+    // an entry's documents, as there's one per sense
+    builder.add_u64_field("hash", INDEXED | FAST);
+    // position of the sense the document is for, 0 for entries without senses
+    builder.add_u64_field("sense", INDEXED | FAST);
 
     // entry fields
     builder.add_text_field("word", jp_options.clone());
     #[allow(clippy::redundant_clone)]
     builder.add_text_field("reading", jp_options.clone());
-    builder.add_text_field("reading_romaji", TEXT | STORED);
+    builder.add_text_field("reading_romaji", TEXT);
+    // the first reading in hiragana, to sort by reading without loading entries
+    builder.add_text_field("reading_sort", FAST);
 
-    // sense fields
-    builder.add_text_field("meaning", TEXT | STORED);
+    // fields of the document's sense, one value per gloss or tag
+    builder.add_text_field("meaning", TEXT);
     // part-of-speech
-    builder.add_text_field("pos", TEXT | STORED);
-    builder.add_text_field("field", TEXT | STORED);
+    builder.add_text_field("pos", TEXT);
+    builder.add_text_field("field", TEXT);
+    builder.add_text_field("misc", TEXT);
+    // the sense's tags again, untokenized, for filtering by exact value
+    for name in TAG_FIELDS {
+        builder.add_text_field(name, STRING);
+    }
+
+    // Which dictionary the document came from, ie. JMdict or an imported Yomitan dictionary
+    builder.add_text_field("source", STRING);
+
+    // The whole `Entry`, bincode-encoded; results are read from this, so none
+    // of the fields above are stored
+    builder.add_bytes_field("entry", STORED);
 
     builder.build()
 }
 
-pub fn create_index(schema: &Schema, path: &str, index: &Index) -> Result<()> {
-    let mut index_writer = index.writer(50_000_000)?;
+/// Keyword fields with the part of speech, field, misc and dialect tags of a
+/// sense's document.
+pub const TAG_FIELDS: [&str; 4] = ["pos_tag", "field_tag", "misc_tag", "dial_tag"];
 
-    // Start with a clean slate
-    index_writer.delete_all_documents()?;
+/// Source name used for documents created from `JMdict`.
+pub const JMDICT_SOURCE: &str = "JMdict";
 
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut gz = GzDecoder::new(reader);
-    let mut parser = EventReader::new(&mut gz);
+/// Part of speech given to documents created from Yomitan kanji banks.
+pub const KANJI_POS: &str = "kanji";
 
-    // common fields
-    let id = schema.get_field("id").unwrap();
+/// Version of the index layout, ie. the schema and how documents are built.
+/// Bump this whenever either changes, so old indexes are rebuilt instead of
+/// giving wrong results or panicking.
+pub const SCHEMA_VERSION: u32 = 8;
 
-    // entry fields
-    let word = schema.get_field("word").unwrap();
-    let reading = schema.get_field("reading").unwrap();
-    let reading_romaji = schema.get_field("reading_romaji").unwrap();
+/// Index-wide metadata, stored in the commit payload and carried over between commits.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct IndexMeta {
+    /// `SCHEMA_VERSION` at the time of the last commit; 0 for indexes from before versioning
+    #[serde(default)]
+    pub schema_version: u32,
+    /// Version of akasabi that made the last commit
+    #[serde(default)]
+    pub generator: String,
+    /// Where the indexed `JMdict` came from
+    #[serde(default)]
+    pub jmdict: Option<Provenance>,
+    /// The `JMdict` entity table (ie. `n` => "noun (common) (futsuumeishi)"), as
+    /// the parser only gives us the expanded values
+    #[serde(default)]
+    pub entities: BTreeMap<String, String>,
+}
 
-    // sense fields
-    let meaning = schema.get_field("meaning").unwrap();
-    let pos = schema.get_field("pos").unwrap();
-    let field = schema.get_field("field").unwrap();
+impl IndexMeta {
+    pub fn load(index: &Index) -> Result<Self> {
+        match index.load_metas()?.payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Ok(IndexMeta::default()),
+        }
+    }
 
-    let mut glosses = Vec::new();
-    // poss?
-    let mut poses = Vec::new();
-    // Can this have >1 value?
-    let mut fields = Vec::new();
+    /// Reads the metadata of the index at `path` without opening it for
+    /// searching, so it works for indexes of any `SCHEMA_VERSION`. `None` if
+    /// there's no index there.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.join("meta.json").exists() {
+            return Ok(None);
+        }
+        let index = Index::open_in_dir(path).context("Failed to open index")?;
+        Ok(Some(IndexMeta::load(&index)?))
+    }
 
-    let mut current_entry = Some(tantivy::Document::default());
+    /// Checks that the index was built with the current `SCHEMA_VERSION`.
+    /// Fails with a `VersionMismatch` if not.
+    pub fn check(index: &Index) -> Result<()> {
+        // A freshly created index has nothing in it that could be out of date
+        if index.searchable_segment_ids()?.is_empty() {
+            return Ok(());
+        }
 
-    let mut count = 0;
+        let meta = IndexMeta::load(index)?;
+        if meta.schema_version == SCHEMA_VERSION {
+            Ok(())
+        } else {
+            Err(VersionMismatch {
+                schema_version: meta.schema_version,
+                generator: meta.generator,
+            }
+            .into())
+        }
+    }
+}
+
+/// Which `JMdict` release an index was built from, for reproducing results.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Provenance {
+    /// Date from the `<!-- JMdict created: YYYY-MM-DD -->` comment
+    pub created: Option<String>,
+    /// Path or URL the file was read from
+    pub source: String,
+    /// SHA-256 of the file as read, ie. before decompression
+    pub sha256: String,
+    pub entries: usize,
+    /// When the index was built (RFC 3339); `None` for a file that was only read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexed_at: Option<String>,
+}
+
+/// How `Provenance` names the file at `path`.
+fn source_name(path: &str) -> String {
+    if path == "-" {
+        "stdin".to_string()
+    } else {
+        std::fs::canonicalize(path)
+            .map_or_else(|_| path.to_string(), |path| path.display().to_string())
+    }
+}
+
+/// The index was built with a different `SCHEMA_VERSION` than this akasabi uses.
+#[derive(Debug)]
+pub struct VersionMismatch {
+    pub schema_version: u32,
+    pub generator: String,
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let generator = if self.generator.is_empty() {
+            String::new()
+        } else {
+            format!(" (v{})", self.generator)
+        };
+        if self.schema_version < SCHEMA_VERSION {
+            write!(
+                f,
+                "index was built by an older akasabi{generator}, run `akasabi index` to rebuild it"
+            )
+        } else {
+            write!(
+                f,
+                "index was built by a newer akasabi{generator}, upgrade akasabi or run `akasabi index` to rebuild it"
+            )
+        }
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+fn commit(index_writer: &mut IndexWriter, meta: &IndexMeta) -> Result<()> {
+    let meta = IndexMeta {
+        schema_version: SCHEMA_VERSION,
+        generator: env!("CARGO_PKG_VERSION").to_string(),
+        ..meta.clone()
+    };
+    let mut prepared = index_writer.prepare_commit()?;
+    prepared.set_payload(&serde_json::to_string(&meta)?);
+    prepared.commit()?;
+    Ok(())
+}
+
+/// Registers the tokenizers referenced by the schema. Must be called before
+/// indexing or searching.
+pub fn register_tokenizers(index: &Index) -> Result<()> {
+    let dictionary = load_dictionary_from_config(DictionaryConfig {
+        kind: Some(DictionaryKind::IPADIC),
+        path: None,
+    })?;
+    let tokenizer = LinderaTokenizer::new(dictionary, None, Mode::Normal);
+    index.tokenizers().register("ja_JP", tokenizer);
+
+    Ok(())
+}
+
+/// What re-indexing `JMdict` changed in the index.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+    /// `JMdict` entries in the index afterwards
+    pub total: usize,
+    /// Entries that couldn't be parsed, in lenient mode
+    pub skipped: Vec<ParseError>,
+    /// Where reading stopped because of malformed XML, in lenient mode
+    pub truncated: Option<ParseError>,
+    /// The existing index was built by an incompatible version of akasabi,
+    /// so it was replaced, dropping imported dictionaries
+    pub rebuilt: bool,
+}
+
+impl std::fmt::Display for Changes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "+{} added, {} changed, {} removed",
+            self.added, self.changed, self.removed
+        )
+    }
+}
+
+/// Hashes an encoded entry.
+fn content_hash(bytes: &[u8]) -> u64 {
+    let digest = sha1_smol::Sha1::from(bytes).digest().bytes();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Matches one document per entry, ie. those for the first sense.
+pub(crate) fn entries_query(schema: &Schema) -> TermQuery {
+    TermQuery::new(
+        Term::from_field_u64(schema.get_field("sense").unwrap(), 0),
+        IndexRecordOption::Basic,
+    )
+}
+
+/// Reads the content hash of every indexed `JMdict` entry, by id.
+fn indexed_hashes(index: &Index) -> Result<HashMap<i64, u64>> {
+    let searcher = index.reader()?.searcher();
+    let mut hashes = HashMap::new();
+    for segment_reader in searcher.segment_readers() {
+        let ids = segment_reader.fast_fields().i64("id")?;
+        let content_hashes = segment_reader.fast_fields().u64("hash")?;
+        for doc in segment_reader.doc_ids_alive() {
+            if let (Some(id), Some(hash)) = (ids.first(doc), content_hashes.first(doc)) {
+                hashes.insert(id, hash);
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Options for `create_index`.
+#[derive(Debug, Clone)]
+pub struct IndexOptions {
+    /// Skip entries that can't be parsed instead of failing
+    pub lenient: bool,
+    /// Number of threads writing the index, up to tantivy's limit
+    pub threads: usize,
+    /// Memory budget of the index writer, in bytes
+    pub writer_memory: usize,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            lenient: false,
+            threads: std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get),
+            writer_memory: DEFAULT_WRITER_MEMORY,
+        }
+    }
+}
+
+pub const DEFAULT_WRITER_MEMORY: usize = 50_000_000;
+/// tantivy won't use more indexing threads than this
+const MAX_WRITER_THREADS: usize = 8;
+/// Below this, segments get too small to be worth another thread
+const MIN_WRITER_MEMORY_PER_THREAD: usize = 15_000_000;
+/// Entries buffered between the parser and the index writer
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A problem with the `JMdict` file, and where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: TextPosition,
+    /// `ent_seq` of the entry the problem was found in, if known
+    pub ent_seq: Option<i64>,
+    pub message: String,
+}
+
+impl ParseError {
+    fn from_xml(error: &xml::reader::Error, ent_seq: Option<i64>) -> Self {
+        let position = error.position();
+        // The error message starts with the position, which we format ourselves
+        let message = error.to_string();
+        let message = message
+            .strip_prefix(&format!("{position} "))
+            .unwrap_or(&message);
+        ParseError {
+            position,
+            ent_seq,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}",
+            self.position.row + 1,
+            self.position.column + 1
+        )?;
+        if let Some(ent_seq) = self.ent_seq {
+            write!(f, " (ent_seq {ent_seq})")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Why handling an XML event failed.
+enum Failure {
+    /// The XML itself is malformed; parsing can't continue
+    Xml(xml::reader::Error),
+    /// The entry being parsed is invalid
+    Entry(String),
+}
+
+impl From<xml::reader::Error> for Failure {
+    fn from(error: xml::reader::Error) -> Self {
+        Failure::Xml(error)
+    }
+}
+
+/// Compression formats dictionary files may come in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Longest magic number we look for.
+    const MAGIC_LEN: usize = 6;
+
+    /// Detects the compression format from the first bytes of a file.
+    #[must_use]
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if header.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Picks the compression format for a file from its extension.
+    #[must_use]
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("xz") => Compression::Xz,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// A dictionary file being read. The raw bytes are hashed as they're read.
+pub struct Input {
+    pub reader: Box<dyn Read + Send>,
+    hasher: Arc<Mutex<Sha256>>,
+}
+
+impl Input {
+    /// SHA-256 (hex) of what was read so far, before decompression.
+    #[must_use]
+    pub fn sha256(&self) -> String {
+        format!("{:x}", self.hasher.lock().unwrap().clone().finalize())
+    }
+}
 
-    while let Ok(e) = parser.next() {
-        match e {
-            XmlEvent::StartElement { name, .. } => match name.local_name.as_str() {
+/// Opens a dictionary file, or stdin for `-`, decompressing it if needed.
+pub fn open_input(path: &str) -> Result<Input> {
+    let hasher = Arc::new(Mutex::new(Sha256::new()));
+    let reader = decompress(HashingReader {
+        inner: open_raw(path)?,
+        hasher: Arc::clone(&hasher),
+    })?;
+    Ok(Input { reader, hasher })
+}
+
+fn open_raw(path: &str) -> Result<Box<dyn Read + Send>> {
+    if path == "-" {
+        Ok(Box::new(io::stdin()))
+    } else {
+        let file = File::open(path).with_context(|| format!("Failed to open {path}"))?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Passes reads through, feeding everything read to a SHA-256 hasher.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Arc<Mutex<Sha256>>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Wraps a reader in the decoder for whatever compression it uses.
+pub fn decompress<R: Read + Send + 'static>(mut reader: R) -> Result<Box<dyn Read + Send>> {
+    // Read the header ourselves, as pipes may return fewer bytes than asked for
+    let mut header = Vec::with_capacity(Compression::MAGIC_LEN);
+    reader
+        .by_ref()
+        .take(Compression::MAGIC_LEN as u64)
+        .read_to_end(&mut header)?;
+    let compression = Compression::detect(&header);
+    let reader = BufReader::new(Cursor::new(header).chain(reader));
+
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+    })
+}
+
+/// Writes `data` to a file, compressed according to its extension.
+pub fn write_compressed(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = io::BufWriter::new(
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    let mut file = match Compression::from_extension(path) {
+        Compression::None => {
+            file.write_all(data)?;
+            file
+        }
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::best());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Compression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(file, 9);
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(file, 19)?;
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Compression::Bzip2 => {
+            let mut encoder = bzip2::write::BzEncoder::new(file, bzip2::Compression::best());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+    };
+    file.flush()?;
+    Ok(())
+}
+
+/// `JMdict` only lists a sense's parts of speech when they differ from the
+/// previous sense's, so fill in the ones left out.
+fn carry_pos_forward(senses: &mut [Sense]) {
+    let mut pos: &[String] = &[];
+    for sense in senses {
+        if sense.pos.is_empty() {
+            sense.pos = pos.to_vec();
+        }
+        pos = &sense.pos;
+    }
+}
+
+/// Reads all entries of a `JMdict` file (or stdin for `-`), by `ent_seq`,
+/// and where they came from.
+pub fn read_entries(path: &str) -> Result<(BTreeMap<i64, Entry>, Provenance)> {
+    let mut entries = BTreeMap::new();
+    let mut input = open_input(path)?;
+    let summary = parse_jmdict(&mut input.reader, false, |entry| {
+        entries.extend(entry.id.map(|id| (id, entry)));
+        true
+    })
+    .with_context(|| format!("Failed to read {path}"))?;
+    let provenance = Provenance {
+        created: summary.created,
+        source: source_name(path),
+        sha256: input.sha256(),
+        entries: entries.len(),
+        indexed_at: None,
+    };
+    Ok((entries, provenance))
+}
+
+/// Everything besides the entries that came out of parsing `JMdict`.
+#[derive(Debug, Default)]
+struct ParseSummary {
+    entities: Option<BTreeMap<String, String>>,
+    /// Date from the `<!-- JMdict created: ... -->` comment
+    created: Option<String>,
+    count: usize,
+    skipped: Vec<ParseError>,
+    /// ids of skipped entries, where known
+    skipped_ids: Vec<i64>,
+    truncated: Option<ParseError>,
+}
+
+fn entry_mut<'a>(
+    current_entry: &'a mut Option<Entry>,
+    element: &str,
+) -> std::result::Result<&'a mut Entry, Failure> {
+    current_entry
+        .as_mut()
+        .ok_or_else(|| Failure::Entry(format!("<{element}> outside of an <entry>")))
+}
+
+fn sense_mut<'a>(
+    current_entry: &'a mut Option<Entry>,
+    element: &str,
+) -> std::result::Result<&'a mut Sense, Failure> {
+    entry_mut(current_entry, element)?
+        .senses
+        .last_mut()
+        .ok_or_else(|| Failure::Entry(format!("<{element}> outside of a <sense>")))
+}
+
+/// Streams entries out of `JMdict` XML, passing each to `emit`. Stops early
+/// if `emit` returns false.
+#[allow(clippy::too_many_lines)]
+fn parse_jmdict<R: Read>(
+    reader: R,
+    lenient: bool,
+    mut emit: impl FnMut(Entry) -> bool,
+) -> Result<ParseSummary> {
+    let mut parser =
+        EventReader::new_with_config(reader, ParserConfig::new().ignore_comments(false));
+    let mut summary = ParseSummary::default();
+    // Set when we stop before the end of the file
+    let mut stopped = false;
+
+    let mut current_entry: Option<Entry> = None;
+    let mut ent_seq: Option<i64> = None;
+    // Set when the current entry is bad, in lenient mode
+    let mut entry_error: Option<ParseError> = None;
+
+    loop {
+        let event = match parser.next() {
+            Ok(event) => event,
+            Err(e) => {
+                let error = ParseError::from_xml(&e, ent_seq);
+                if !lenient {
+                    return Err(error.into());
+                }
+                // The parser can't recover from malformed XML, so stop here
+                summary.truncated = Some(error);
+                break;
+            }
+        };
+
+        match &event {
+            XmlEvent::EndDocument => {
+                // NB: Parser will repeatedly return EndDocument, so we need to break out of the loop
+                break;
+            }
+            XmlEvent::Comment(text) if summary.created.is_none() => {
+                if let Some(date) = text.trim().strip_prefix("JMdict created:") {
+                    summary.created = Some(date.trim().to_string());
+                }
+                continue;
+            }
+            XmlEvent::EndElement { name } if name.local_name == "entry" => {
+                summary.count += 1;
+
+                let entry = current_entry.take();
+                let error = entry_error.take().or_else(|| {
+                    ent_seq.is_none().then(|| ParseError {
+                        position: parser.position(),
+                        ent_seq: None,
+                        message: "entry has no <ent_seq>".to_string(),
+                    })
+                });
+                if let Some(error) = error {
+                    if !lenient {
+                        return Err(error.into());
+                    }
+                    summary.skipped_ids.extend(ent_seq);
+                    summary.skipped.push(error);
+                    continue;
+                }
+
+                if let (Some(mut entry), Some(entry_id)) = (entry, ent_seq) {
+                    entry.id = Some(entry_id);
+                    carry_pos_forward(&mut entry.senses);
+                    if !emit(entry) {
+                        stopped = true;
+                        break;
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let outcome = (|| -> std::result::Result<(), Failure> {
+            let XmlEvent::StartElement { name, .. } = event else {
+                return Ok(());
+            };
+            match name.local_name.as_str() {
+                "JMdict" => {
+                    if let Some(doctype) = parser.doctype() {
+                        summary.entities = Some(parse_entities(doctype));
+                    }
+                }
                 "entry" => {
-                    current_entry = Some(tantivy::Document::default());
+                    current_entry = Some(Entry {
+                        source: JMDICT_SOURCE.to_string(),
+                        ..Entry::default()
+                    });
+                    ent_seq = None;
                 }
                 "sense" => {
-                    glosses.clear();
-                    poses.clear();
-                    fields.clear();
+                    entry_mut(&mut current_entry, "sense")?
+                        .senses
+                        .push(Sense::default());
                 }
                 "ent_seq" => {
-                    let entry_id = extract_next_string(&mut parser);
-                    current_entry
-                        .as_mut()
-                        .unwrap()
-                        .add_i64(id, entry_id.parse::<i64>().unwrap());
+                    let entry_id = extract_next_string(&mut parser)?;
+                    let entry_id = entry_id
+                        .trim()
+                        .parse::<i64>()
+                        .map_err(|_| Failure::Entry(format!("invalid <ent_seq> {entry_id:?}")))?;
+                    entry_mut(&mut current_entry, "ent_seq")?;
+                    ent_seq = Some(entry_id);
                 }
                 "keb" => {
-                    let keb = extract_next_string(&mut parser);
-                    current_entry.as_mut().unwrap().add_text(word, keb);
+                    let keb = extract_next_string(&mut parser)?;
+                    entry_mut(&mut current_entry, "keb")?
+                        .kanji
+                        .push(KanjiForm { text: keb });
                 }
                 "reb" => {
-                    let reb = extract_next_string(&mut parser);
-                    current_entry
-                        .as_mut()
-                        .unwrap()
-                        .add_text(reading, reb.clone());
-                    current_entry
-                        .as_mut()
-                        .unwrap()
-                        .add_text(reading_romaji, reb.to_romaji());
+                    let reb = extract_next_string(&mut parser)?;
+                    entry_mut(&mut current_entry, "reb")?
+                        .readings
+                        .push(Reading { text: reb });
                 }
                 "gloss" => {
-                    let gloss = extract_next_string(&mut parser);
-                    glosses.push(gloss);
+                    let gloss = extract_next_string(&mut parser)?;
+                    sense_mut(&mut current_entry, "gloss")?.glosses.push(gloss);
                 }
                 "pos" => {
-                    let pos_value = extract_next_string(&mut parser);
-                    poses.push(pos_value);
+                    let pos = extract_next_string(&mut parser)?;
+                    sense_mut(&mut current_entry, "pos")?.pos.push(pos);
                 }
                 "field" => {
-                    let field_value = extract_next_string(&mut parser);
-                    fields.push(field_value);
+                    let field = extract_next_string(&mut parser)?;
+                    sense_mut(&mut current_entry, "field")?.field.push(field);
+                }
+                "misc" => {
+                    let misc = extract_next_string(&mut parser)?;
+                    sense_mut(&mut current_entry, "misc")?.misc.push(misc);
+                }
+                "dial" => {
+                    let dial = extract_next_string(&mut parser)?;
+                    sense_mut(&mut current_entry, "dial")?.dial.push(dial);
+                }
+                "xref" => {
+                    let xref = Reference::parse(&extract_next_string(&mut parser)?);
+                    sense_mut(&mut current_entry, "xref")?.xref.push(xref);
+                }
+                "ant" => {
+                    let ant = Reference::parse(&extract_next_string(&mut parser)?);
+                    sense_mut(&mut current_entry, "ant")?.ant.push(ant);
                 }
                 _ => {}
-            },
-            XmlEvent::EndElement { name } => {
-                if name.local_name == "entry" {
-                    let current_doc = current_entry.take().unwrap();
-                    index_writer.add_document(current_doc)?;
+            }
+            Ok(())
+        })();
+
+        match outcome {
+            Ok(()) => {}
+            Err(Failure::Xml(e)) => {
+                let error = ParseError::from_xml(&e, ent_seq);
+                if !lenient {
+                    return Err(error.into());
+                }
+                summary.truncated = Some(error);
+                break;
+            }
+            Err(Failure::Entry(message)) => {
+                let error = ParseError {
+                    position: parser.position(),
+                    ent_seq,
+                    message,
+                };
+                if !lenient {
+                    return Err(error.into());
+                }
+                entry_error.get_or_insert(error);
+            }
+        }
+    }
+
+    // Read whatever follows the document, so that the whole input is hashed
+    if !stopped {
+        io::copy(&mut parser.into_inner(), &mut io::sink()).ok();
+    }
+    Ok(summary)
+}
+
+/// Builds the documents for an entry, one per sense so that meaning searches
+/// can be ranked by sense, and returns them with the entry's hash. For
+/// `JMdict` this is where the expensive per-entry work besides tokenization
+/// (which tantivy does) happens.
+fn entry_documents(schema: &Schema, entry: &Entry) -> (u64, Vec<tantivy::Document>) {
+    let field = |name| schema.get_field(name).unwrap();
+    let (reading, reading_romaji) = (field("reading"), field("reading_romaji"));
+    let bytes = entry.to_bytes();
+    let content = content_hash(&bytes);
+
+    // What every sense's document has
+    let mut document = tantivy::Document::default();
+    document.add_text(field("source"), &entry.source);
+    if let Some(id) = entry.id {
+        document.add_i64(field("id"), id);
+    }
+    document.add_u64(field("hash"), content);
+    for keb in &entry.kanji {
+        document.add_text(field("word"), &keb.text);
+    }
+    for reb in &entry.readings {
+        document.add_text(reading, &reb.text);
+        document.add_text(reading_romaji, reb.text.to_romaji());
+    }
+    if let Some(reb) = entry.readings.first() {
+        document.add_text(field("reading_sort"), reb.text.to_hiragana());
+    }
+    document.add_bytes(field("entry"), bytes);
+
+    // Entries without senses, eg. imported frequencies, still need a document
+    if entry.senses.is_empty() {
+        document.add_u64(field("sense"), 0);
+        return (content, vec![document]);
+    }
+    let documents = entry
+        .senses
+        .iter()
+        .zip(0..)
+        .map(|(sense, position)| {
+            let mut document = document.clone();
+            document.add_u64(field("sense"), position);
+            for (name, values) in [
+                ("meaning", &sense.glosses),
+                ("pos", &sense.pos),
+                ("field", &sense.field),
+                ("misc", &sense.misc),
+            ] {
+                for value in values {
+                    document.add_text(field(name), value);
+                }
+            }
+            let tags = [&sense.pos, &sense.field, &sense.misc, &sense.dial];
+            for (name, values) in TAG_FIELDS.into_iter().zip(tags) {
+                for value in values {
+                    document.add_text(field(name), value);
+                }
+            }
+            document
+        })
+        .collect();
+    (content, documents)
+}
+
+/// How far `create_index` has got, for showing progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// This many entries were read so far; reported every 1000 entries
+    Read(usize),
+    /// All of this many entries were read, and the index is being committed
+    Committing(usize),
+}
+
+/// Opens an index writer with the threads and memory budget of `options`.
+fn writer(index: &Index, options: &IndexOptions) -> Result<IndexWriter> {
+    let threads = options
+        .threads
+        .min(MAX_WRITER_THREADS)
+        .min(options.writer_memory / MIN_WRITER_MEMORY_PER_THREAD)
+        .max(1);
+    Ok(index.writer_with_num_threads(threads, options.writer_memory)?)
+}
+
+/// Indexes a `JMdict` file. Only entries that were added or changed since the
+/// last run are (re-)indexed, and entries no longer in the file are removed.
+///
+/// A thread parses the XML while the calling thread turns the entries into
+/// documents for the index writer, whose `options.threads` threads tokenize
+/// them. Parsing and writing take about as long as each other, and building
+/// documents a tenth of that, so it isn't worth threads of its own.
+pub fn create_index(
+    schema: &Schema,
+    path: &str,
+    index: &Index,
+    options: &IndexOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Changes> {
+    let mut index_writer = writer(index, options)?;
+    let mut meta = IndexMeta::load(index)?;
+    let mut stale = indexed_hashes(index)?;
+    let mut changes = Changes::default();
+    let previous = stale.len();
 
-                    count += 1;
+    let id = schema.get_field("id").unwrap();
+
+    let mut input = open_input(path)?;
+    let reader = &mut input.reader;
+    let lenient = options.lenient;
+
+    let (entry_sender, entry_receiver) = mpsc::sync_channel::<Entry>(CHANNEL_CAPACITY);
+
+    let summary = std::thread::scope(|scope| -> Result<ParseSummary> {
+        let parser = scope.spawn(move || {
+            // Sending fails once the receiver is gone, ie. after an error
+            parse_jmdict(reader, lenient, |entry| entry_sender.send(entry).is_ok())
+        });
+
+        let mut count = 0;
+        for entry in entry_receiver {
+            count += 1;
+            let entry_id = entry.id.expect("parsed entries have an ent_seq");
+            let (content, documents) = entry_documents(schema, &entry);
+            if count % 1000 == 0 {
+                progress(Progress::Read(count));
+            }
 
-                    if count % 1000 == 0 {
-                        println!("{} entries read...", Paint::default(count).bold());
+            match stale.remove(&entry_id) {
+                Some(previous) if previous == content => {}
+                previous => {
+                    if previous.is_some() {
+                        index_writer.delete_term(Term::from_field_i64(id, entry_id));
+                        changes.changed += 1;
+                    } else {
+                        changes.added += 1;
                     }
-                } else if name.local_name == "sense" {
-                    if let Some(entry) = current_entry.as_mut() {
-                        entry.add_text(meaning, glosses.join("; "));
-                        entry.add_text(pos, poses.join("; "));
-                        entry.add_text(field, fields.join("; "));
+                    for document in documents {
+                        index_writer.add_document(document)?;
                     }
                 }
             }
-            XmlEvent::EndDocument => {
-                // NB: Parser will repeatedly return EndDocument, so we need to break out of the loop
-                break;
+        }
+
+        parser
+            .join()
+            .map_err(|_| anyhow::anyhow!("JMdict parser thread panicked"))?
+    })?;
+
+    if let Some(entities) = summary.entities {
+        meta.entities = entities;
+    }
+    // Keep whatever was indexed for skipped entries before
+    for entry_id in &summary.skipped_ids {
+        stale.remove(entry_id);
+    }
+    changes.skipped = summary.skipped;
+    changes.truncated = summary.truncated;
+
+    // Whatever wasn't seen is no longer in JMdict, unless we didn't get to read everything
+    if changes.truncated.is_none() {
+        for entry_id in stale.keys() {
+            index_writer.delete_term(Term::from_field_i64(id, *entry_id));
+        }
+        changes.removed = stale.len();
+    }
+    changes.total = previous + changes.added - changes.removed;
+    meta.jmdict = Some(Provenance {
+        created: summary.created,
+        source: source_name(path),
+        sha256: input.sha256(),
+        entries: changes.total,
+        indexed_at: Some(
+            time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)?,
+        ),
+    });
+
+    progress(Progress::Committing(summary.count));
+    commit(&mut index_writer, &meta)?;
+
+    Ok(changes)
+}
+
+/// Parses `<!ENTITY name "value">` declarations from a doctype.
+fn parse_entities(doctype: &str) -> BTreeMap<String, String> {
+    doctype
+        .split("<!ENTITY")
+        .skip(1)
+        .filter_map(|decl| {
+            let (name, rest) = decl.trim_start().split_once(char::is_whitespace)?;
+            let value = rest.trim_start().strip_prefix('"')?.split('"').next()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The source name for documents imported from a dictionary with this
+/// title. A dictionary titled "`JMdict`" gets a different one, as replacing
+/// it would delete the indexed `JMdict` entries.
+#[must_use]
+pub fn import_source(title: &str) -> String {
+    if title == JMDICT_SOURCE {
+        format!("{title} (Yomitan)")
+    } else {
+        title.to_string()
+    }
+}
+
+/// Adds the contents of a Yomitan dictionary to the index, replacing any
+/// documents previously imported from a dictionary with the same title.
+/// Returns the number of entries imported.
+#[allow(clippy::too_many_lines)]
+pub fn import_yomitan(
+    schema: &Schema,
+    dictionary: &yomitan::Dictionary,
+    index: &Index,
+    options: &IndexOptions,
+) -> Result<usize> {
+    let mut index_writer = writer(index, options)?;
+
+    let source = import_source(&dictionary.index.title);
+    let title = source.as_str();
+    let tags: HashMap<_, _> = dictionary
+        .tags
+        .iter()
+        .map(|t| (t.name.as_str(), t))
+        .collect();
+    index_writer.delete_term(Term::from_field_text(
+        schema.get_field("source").unwrap(),
+        title,
+    ));
+
+    // Yomitan only has expression and reading, so split them into JMdict's keb and reb
+    let new_entry = |expression: &str, kana: &str| {
+        // An empty reading means the expression is its own reading, or
+        // (for frequency lists) that the reading is unknown
+        let kana = match kana {
+            "" if expression.is_kana() => expression,
+            kana => kana,
+        };
+        Entry {
+            source: title.to_string(),
+            kanji: (!expression.is_kana())
+                .then(|| KanjiForm {
+                    text: expression.to_string(),
+                })
+                .into_iter()
+                .collect(),
+            readings: (!kana.is_empty())
+                .then(|| Reading {
+                    text: kana.to_string(),
+                })
+                .into_iter()
+                .collect(),
+            ..Entry::default()
+        }
+    };
+
+    // Rows sharing expression, reading and sequence number are senses of the same entry
+    let terms = dictionary
+        .terms
+        .iter()
+        .into_group_map_by(|t| (t.expression.as_str(), t.reading.as_str(), t.sequence));
+    let mut count = 0;
+    for term in dictionary
+        .terms
+        .iter()
+        .unique_by(|t| (&t.expression, &t.reading, t.sequence))
+    {
+        let mut entry = new_entry(&term.expression, &term.reading);
+        for sense in &terms[&(
+            term.expression.as_str(),
+            term.reading.as_str(),
+            term.sequence,
+        )] {
+            let (pos, field, misc) = sort_tags(&sense.definition_tags, &tags);
+            entry.senses.push(Sense {
+                glosses: sense.glossary.clone(),
+                pos,
+                field,
+                misc,
+                ..Sense::default()
+            });
+        }
+        for document in entry_documents(schema, &entry).1 {
+            index_writer.add_document(document)?;
+        }
+        count += 1;
+    }
+
+    for meta in &dictionary.term_meta {
+        let entry = match &meta.data {
+            yomitan::MetaData::Frequency {
+                reading: kana,
+                value,
+            } => Entry {
+                frequency: Some(*value),
+                ..new_entry(&meta.expression, kana.as_deref().unwrap_or_default())
+            },
+            yomitan::MetaData::Pitch {
+                reading: kana,
+                positions,
+            } => Entry {
+                pitch: positions.iter().map(ToString::to_string).collect(),
+                ..new_entry(&meta.expression, kana)
+            },
+        };
+        for document in entry_documents(schema, &entry).1 {
+            index_writer.add_document(document)?;
+        }
+        count += 1;
+    }
+
+    for kanji in &dictionary.kanji {
+        let entry = Entry {
+            source: title.to_string(),
+            kanji: vec![KanjiForm {
+                text: kanji.character.clone(),
+            }],
+            readings: kanji
+                .onyomi
+                .iter()
+                .chain(&kanji.kunyomi)
+                .map(|kana| Reading { text: kana.clone() })
+                .collect(),
+            senses: vec![Sense {
+                glosses: kanji.meanings.clone(),
+                pos: vec![KANJI_POS.to_string()],
+                field: kanji.tags.clone(),
+                ..Sense::default()
+            }],
+            ..Entry::default()
+        };
+        for document in entry_documents(schema, &entry).1 {
+            index_writer.add_document(document)?;
+        }
+        count += 1;
+    }
+
+    commit(&mut index_writer, &IndexMeta::load(index)?)?;
+
+    Ok(count)
+}
+
+/// Sorts Yomitan tags into pos, field and misc by their category, preferring
+/// the description over the tag name.
+fn sort_tags(
+    names: &[String],
+    tags: &HashMap<&str, &yomitan::Tag>,
+) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let (mut poses, mut fields, mut miscs) = (Vec::new(), Vec::new(), Vec::new());
+    for name in names {
+        let tag = tags.get(name.as_str());
+        let value = match tag {
+            Some(tag) if !tag.notes.is_empty() => tag.notes.clone(),
+            _ => name.clone(),
+        };
+        match tag.map(|t| t.category.as_str()) {
+            Some("field") => fields.push(value),
+            Some("misc" | "archaism" | "popular" | "frequent") => miscs.push(value),
+            _ => poses.push(value),
+        }
+    }
+    (poses, fields, miscs)
+}
+
+/// Builds a Yomitan dictionary from every document in the index, including
+/// those from imported dictionaries.
+#[allow(clippy::too_many_lines)]
+pub fn export_yomitan(schema: &Schema, index: &Index, title: &str) -> Result<yomitan::Dictionary> {
+    // Yomitan tags can't contain spaces, so map JMdict's descriptions back to entity names
+    let codes: HashMap<String, String> = IndexMeta::load(index)?
+        .entities
+        .into_iter()
+        .map(|(code, description)| (description, code))
+        .collect();
+    let mut tags = BTreeMap::new();
+    let mut tag = |description: &str, category: &str| {
+        let name = codes
+            .get(description)
+            .cloned()
+            .unwrap_or_else(|| description.replace(char::is_whitespace, "-"));
+        tags.entry(name.clone()).or_insert_with(|| yomitan::Tag {
+            name: name.clone(),
+            category: category.to_string(),
+            notes: description.to_string(),
+        });
+        name
+    };
+
+    let mut dictionary = yomitan::Dictionary {
+        index: yomitan::DictionaryIndex {
+            title: title.to_string(),
+            revision: format!("akasabi {}", env!("CARGO_PKG_VERSION")),
+            format: Some(3),
+            description: Some("Exported from an akasabi index".to_string()),
+        },
+        ..yomitan::Dictionary::default()
+    };
+
+    let searcher = index.reader()?.searcher();
+    let addresses = searcher
+        .search(&entries_query(schema), &tantivy::collector::DocSetCollector)?
+        .into_iter()
+        .sorted();
+
+    for address in addresses {
+        let entry = Entry::from_document(schema, &searcher.doc(address)?)?;
+        let kanji = entry.kanji.into_iter().map(|k| k.text).collect_vec();
+        let readings = entry.readings.into_iter().map(|r| r.text).collect_vec();
+        let expression = kanji
+            .first()
+            .or(readings.first())
+            .cloned()
+            .unwrap_or_default();
+        let first_reading = if kanji.is_empty() {
+            String::new()
+        } else {
+            readings.first().cloned().unwrap_or_default()
+        };
+
+        if let Some(value) = entry.frequency {
+            dictionary.term_meta.push(yomitan::TermMeta {
+                expression,
+                data: yomitan::MetaData::Frequency {
+                    reading: (!first_reading.is_empty()).then_some(first_reading),
+                    value,
+                },
+            });
+            continue;
+        }
+        if !entry.pitch.is_empty() {
+            dictionary.term_meta.push(yomitan::TermMeta {
+                expression,
+                data: yomitan::MetaData::Pitch {
+                    reading: first_reading,
+                    positions: entry.pitch.iter().filter_map(|p| p.parse().ok()).collect(),
+                },
+            });
+            continue;
+        }
+
+        if let [sense] = entry.senses.as_slice() {
+            if sense.pos == [KANJI_POS] {
+                let (onyomi, kunyomi) =
+                    readings.into_iter().partition(|r| r.as_str().is_katakana());
+                dictionary.kanji.push(yomitan::Kanji {
+                    character: expression,
+                    onyomi,
+                    kunyomi,
+                    tags: sense.field.clone(),
+                    meanings: sense.glosses.clone(),
+                });
+                continue;
+            }
+        }
+
+        let sequence = entry.id.unwrap_or_default();
+        let headwords = if kanji.is_empty() {
+            readings
+                .iter()
+                .map(|r| (r.clone(), String::new()))
+                .collect_vec()
+        } else if readings.is_empty() {
+            kanji
+                .iter()
+                .map(|k| (k.clone(), String::new()))
+                .collect_vec()
+        } else {
+            kanji
+                .iter()
+                .cartesian_product(&readings)
+                .map(|(k, r)| (k.clone(), r.clone()))
+                .collect_vec()
+        };
+
+        let senses = entry
+            .senses
+            .iter()
+            .map(|sense| {
+                let rules = sense
+                    .pos
+                    .iter()
+                    .filter_map(|p| {
+                        deinflection_rule(codes.get(p).map_or(p.as_str(), String::as_str))
+                    })
+                    .unique()
+                    .map(ToString::to_string)
+                    .collect_vec();
+                let mut definition_tags = Vec::new();
+                for (values, category) in [
+                    (&sense.pos, "partOfSpeech"),
+                    (&sense.field, "field"),
+                    (&sense.misc, "misc"),
+                ] {
+                    definition_tags.extend(values.iter().map(|v| tag(v, category)));
+                }
+                (definition_tags, rules, sense.glosses.clone())
+            })
+            .collect_vec();
+
+        for (expression, kana) in headwords {
+            for (definition_tags, rules, glossary) in &senses {
+                dictionary.terms.push(yomitan::Term {
+                    expression: expression.clone(),
+                    reading: kana.clone(),
+                    definition_tags: definition_tags.clone(),
+                    rules: rules.clone(),
+                    score: 0,
+                    glossary: glossary.clone(),
+                    sequence,
+                    tags: Vec::new(),
+                });
             }
-            _ => {}
         }
     }
 
-    print!(
-        "{} entries read... ",
-        Paint::default(count.to_string()).bold()
-    );
-    // Flush stdout so that the progress indicator is displayed
-    io::stdout().flush().unwrap();
-    index_writer.commit()?;
-    println!("and committed.");
+    dictionary.tags = tags.into_values().collect();
 
-    Ok(())
+    Ok(dictionary)
 }
 
-fn extract_next_string<R: Read>(parser: &mut EventReader<R>) -> String {
+/// Maps a `JMdict` part of speech code to the Yomitan deinflection rule it belongs to.
+fn deinflection_rule(pos: &str) -> Option<&'static str> {
+    match pos {
+        "adj-i" => Some("adj-i"),
+        "vk" => Some("vk"),
+        "vz" => Some("vz"),
+        _ if pos.starts_with("v1") => Some("v1"),
+        _ if pos.starts_with("v5") => Some("v5"),
+        _ if pos.starts_with("vs") => Some("vs"),
+        _ => None,
+    }
+}
+
+fn extract_next_string<R: Read>(parser: &mut EventReader<R>) -> xml::reader::Result<String> {
     let mut buf = String::new();
     loop {
-        match parser.next().unwrap() {
+        match parser.next()? {
             XmlEvent::Characters(s) => {
                 buf.push_str(&s);
             }
-            XmlEvent::EndElement { name } => {
-                if name.local_name == "keb"
-                    || name.local_name == "reb"
-                    || name.local_name == "gloss"
-                    || name.local_name == "pos"
-                    || name.local_name == "field"
-                    || name.local_name == "ent_seq"
-                {
-                    break;
-                }
+            XmlEvent::EndElement { name }
+                if matches!(
+                    name.local_name.as_str(),
+                    "keb"
+                        | "reb"
+                        | "gloss"
+                        | "pos"
+                        | "field"
+                        | "misc"
+                        | "dial"
+                        | "xref"
+                        | "ant"
+                        | "ent_seq"
+                ) =>
+            {
+                break;
             }
             _ => {}
         }
     }
-    buf
+    Ok(buf)
 }
 
+#[allow(dead_code)] // TODO: Wire up to `index --jmdict-url`
 fn fetch_jmdict<P: AsRef<Path>>(out_file: P) -> Result<()> {
     let url = "https://ftp.monash.edu/pub/nihongo/JMdict_e.gz";
     let mut resp = reqwest::blocking::get(url)?;
@@ -191,13 +1333,18 @@ fn fetch_jmdict<P: AsRef<Path>>(out_file: P) -> Result<()> {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
 
+    /// Commits with the current index metadata, for tests elsewhere.
+    pub(crate) fn commit_meta(index_writer: &mut IndexWriter) {
+        commit(index_writer, &IndexMeta::default()).unwrap();
+    }
+
     #[test]
     fn test_extract_next_string() {
         let mut parser = EventReader::from_str(
-            r#"
+            r"
             <entry>
                 <ent_seq>1</ent_seq>
                 <k_ele>
@@ -215,18 +1362,18 @@ mod test {
                     <field>country</field>
                 </sense>
             </entry>
-        "#,
+        ",
         );
 
-        assert_eq!(extract_next_string(&mut parser), "1");
-        assert_eq!(extract_next_string(&mut parser), "日本");
-        assert_eq!(extract_next_string(&mut parser), "にほん");
-        assert_eq!(extract_next_string(&mut parser), "Japan");
-        assert_eq!(extract_next_string(&mut parser), "Japanese");
-        assert_eq!(extract_next_string(&mut parser), "noun");
-        assert_eq!(extract_next_string(&mut parser), "proper noun");
-        assert_eq!(extract_next_string(&mut parser), "place");
-        assert_eq!(extract_next_string(&mut parser), "country");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "1");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "日本");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "にほん");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "Japan");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "Japanese");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "noun");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "proper noun");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "place");
+        assert_eq!(extract_next_string(&mut parser).unwrap(), "country");
     }
 
     #[test]
@@ -236,6 +1383,632 @@ mod test {
         let index_path = tempfile::tempdir().unwrap();
         let schema = create_schema();
         let index = Index::create_in_dir(index_path.path(), schema.clone()).unwrap();
-        create_index(&schema, jmdict_path.to_str().unwrap(), &index).unwrap();
+        register_tokenizers(&index).unwrap();
+        let mut progress = Vec::new();
+        let changes = create_index(
+            &schema,
+            jmdict_path.to_str().unwrap(),
+            &index,
+            &IndexOptions::default(),
+            &mut |p| progress.push(p),
+        )
+        .unwrap();
+        assert_eq!(progress, vec![Progress::Committing(101)]);
+        assert_eq!(
+            changes,
+            Changes {
+                added: 101,
+                changed: 0,
+                removed: 0,
+                total: 101,
+                ..Changes::default()
+            }
+        );
+
+        let provenance = IndexMeta::load(&index).unwrap().jmdict.unwrap();
+        assert_eq!(provenance.created.as_deref(), Some("2023-06-14"));
+        assert_eq!(provenance.entries, 101);
+        assert!(provenance.source.ends_with("JMdict_e_test.gz"));
+        let sha256 = Sha256::digest(std::fs::read(jmdict_path).unwrap());
+        assert_eq!(provenance.sha256, format!("{sha256:x}"));
+    }
+
+    fn write_jmdict(path: &Path, entries: &[(i64, &str, &str)]) {
+        let entries = entries
+            .iter()
+            .map(|(ent_seq, reb, gloss)| {
+                format!(
+                    "<entry><ent_seq>{ent_seq}</ent_seq><r_ele><reb>{reb}</reb></r_ele>\
+                     <sense><pos>noun</pos><gloss>{gloss}</gloss></sense></entry>"
+                )
+            })
+            .join("");
+        write_gz(path, &format!("<JMdict>{entries}</JMdict>"));
+    }
+
+    fn write_gz(path: &Path, contents: &str) {
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(path).unwrap(), flate2::Compression::fast());
+        encoder.write_all(contents.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut xml = Vec::new();
+        open_input("testdata/JMdict_e_test.gz")
+            .unwrap()
+            .reader
+            .read_to_end(&mut xml)
+            .unwrap();
+        assert!(xml.starts_with(b"<?xml"));
+
+        let compressed = [
+            (Compression::None, xml.clone()),
+            (Compression::Gzip, {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(&xml).unwrap();
+                encoder.finish().unwrap()
+            }),
+            (Compression::Xz, {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+                encoder.write_all(&xml).unwrap();
+                encoder.finish().unwrap()
+            }),
+            (
+                Compression::Zstd,
+                zstd::encode_all(xml.as_slice(), 1).unwrap(),
+            ),
+            (Compression::Bzip2, {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                encoder.write_all(&xml).unwrap();
+                encoder.finish().unwrap()
+            }),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        for (compression, bytes) in compressed {
+            assert_eq!(Compression::detect(&bytes), compression);
+
+            let path = dir.path().join(format!("JMdict_{compression:?}"));
+            std::fs::write(&path, &bytes).unwrap();
+            let mut decompressed = Vec::new();
+            open_input(path.to_str().unwrap())
+                .unwrap()
+                .reader
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert!(decompressed == xml, "{compression:?}");
+        }
+
+        // Inputs shorter than any magic number are read as they are
+        let mut short = Vec::new();
+        decompress(Cursor::new(b"<a/>".to_vec()))
+            .unwrap()
+            .read_to_end(&mut short)
+            .unwrap();
+        assert_eq!(short, b"<a/>");
+    }
+
+    #[test]
+    fn test_create_index_plain_xml() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict_e.xml");
+        let mut xml = Vec::new();
+        open_input("testdata/JMdict_e_test.gz")
+            .unwrap()
+            .reader
+            .read_to_end(&mut xml)
+            .unwrap();
+        std::fs::write(&jmdict_path, xml).unwrap();
+
+        let schema = create_schema();
+        let index = Index::create_in_ram(schema.clone());
+        register_tokenizers(&index).unwrap();
+        let changes = create_index(
+            &schema,
+            jmdict_path.to_str().unwrap(),
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(changes.added, 101);
+    }
+
+    #[test]
+    fn test_create_index_incremental() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.gz");
+        let jmdict_path_str = jmdict_path.to_str().unwrap();
+        let schema = create_schema();
+        let index = Index::create_in_ram(schema.clone());
+        register_tokenizers(&index).unwrap();
+
+        write_jmdict(
+            &jmdict_path,
+            &[
+                (1, "あか", "red"),
+                (2, "さび", "rust"),
+                (3, "みず", "water"),
+            ],
+        );
+        let changes = create_index(
+            &schema,
+            jmdict_path_str,
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(changes.added, 3);
+
+        // Nothing changed
+        let changes = create_index(
+            &schema,
+            jmdict_path_str,
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            Changes {
+                total: 3,
+                ..Changes::default()
+            }
+        );
+
+        write_jmdict(
+            &jmdict_path,
+            &[
+                (1, "あか", "red"),
+                (2, "さび", "rust; corrosion"),
+                (4, "ひ", "fire"),
+            ],
+        );
+        let changes = create_index(
+            &schema,
+            jmdict_path_str,
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            Changes {
+                added: 1,
+                changed: 1,
+                removed: 1,
+                total: 3,
+                ..Changes::default()
+            }
+        );
+
+        let searcher = index.reader().unwrap().searcher();
+        let meanings = |entry_id| {
+            let query = tantivy::query::TermQuery::new(
+                Term::from_field_i64(schema.get_field("id").unwrap(), entry_id),
+                tantivy::schema::IndexRecordOption::Basic,
+            );
+            searcher
+                .search(&query, &tantivy::collector::TopDocs::with_limit(10))
+                .unwrap()
+                .iter()
+                .map(|(_, address)| {
+                    let doc = searcher.doc(*address).unwrap();
+                    Entry::from_document(&schema, &doc).unwrap().senses[0]
+                        .glosses
+                        .clone()
+                })
+                .collect_vec()
+        };
+        assert_eq!(meanings(1), vec![vec!["red"]]);
+        // A single gloss, even though it contains "; "
+        assert_eq!(meanings(2), vec![vec!["rust; corrosion"]]);
+        assert!(meanings(3).is_empty());
+        assert_eq!(meanings(4), vec![vec!["fire"]]);
+    }
+
+    #[test]
+    fn test_carry_pos_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.gz");
+        write_gz(
+            &jmdict_path,
+            "<JMdict>\n\
+             <entry><ent_seq>1</ent_seq><r_ele><reb>とる</reb></r_ele>\
+             <sense><pos>verb</pos><gloss>to take</gloss></sense>\
+             <sense><gloss>to pick up</gloss></sense>\
+             <sense><pos>noun</pos><gloss>taking</gloss></sense>\
+             <sense><gloss>take</gloss></sense></entry>\n\
+             </JMdict>",
+        );
+        let (entries, provenance) = read_entries(jmdict_path.to_str().unwrap()).unwrap();
+        assert_eq!((provenance.entries, provenance.indexed_at), (1, None));
+        let pos = entries[&1]
+            .senses
+            .iter()
+            .map(|s| s.pos.join(";"))
+            .collect_vec();
+        assert_eq!(pos, vec!["verb", "verb", "noun", "noun"]);
+    }
+
+    #[test]
+    fn test_create_index_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.gz");
+        let jmdict_path_str = jmdict_path.to_str().unwrap();
+        let schema = create_schema();
+        let index = Index::create_in_ram(schema.clone());
+        register_tokenizers(&index).unwrap();
+        let lenient = IndexOptions {
+            lenient: true,
+            ..IndexOptions::default()
+        };
+
+        write_jmdict(&jmdict_path, &[(1, "あか", "red"), (2, "さび", "rust")]);
+        create_index(
+            &schema,
+            jmdict_path_str,
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+
+        write_gz(
+            &jmdict_path,
+            "<JMdict>\n\
+             <entry><ent_seq>1</ent_seq><r_ele><reb>あか</reb></r_ele></entry>\n\
+             <entry><ent_seq>x2</ent_seq><r_ele><reb>さび</reb></r_ele></entry>\n\
+             <entry><r_ele><reb>みず</reb></r_ele></entry>\n\
+             <entry><ent_seq>4</ent_seq><r_ele><reb>ひ</reb></r_ele></entry>\n\
+             </JMdict>",
+        );
+        let error = create_index(
+            &schema,
+            jmdict_path_str,
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap_err()
+        .downcast::<ParseError>()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            r#"line 3, column 19: invalid <ent_seq> "x2""#
+        );
+
+        let changes =
+            create_index(&schema, jmdict_path_str, &index, &lenient, &mut |_| {}).unwrap();
+        assert_eq!(changes.skipped.len(), 2);
+        assert_eq!(
+            changes.skipped[1].to_string(),
+            "line 4, column 36: entry has no <ent_seq>"
+        );
+        // Entry 1 changed (its sense is gone), and 2 is removed as its ent_seq is unreadable
+        assert_eq!((changes.added, changes.changed, changes.removed), (1, 1, 1));
+
+        // Malformed XML can't be skipped over, so reading stops there
+        write_gz(
+            &jmdict_path,
+            "<JMdict>\n\
+             <entry><ent_seq>5</ent_seq><r_ele><reb>き</reb></r_ele></entry>\n\
+             <entry><ent_seq>6</ent_seq><r_ele><reb>つち</reb></r_ele>\n\
+             </JMdict>",
+        );
+        let error = create_index(
+            &schema,
+            jmdict_path_str,
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap_err()
+        .downcast::<ParseError>()
+        .unwrap();
+        assert_eq!(error.ent_seq, Some(6));
+        assert_eq!(error.position.row + 1, 4);
+
+        let changes =
+            create_index(&schema, jmdict_path_str, &index, &lenient, &mut |_| {}).unwrap();
+        assert!(changes.truncated.is_some());
+        assert_eq!((changes.added, changes.removed), (1, 0));
+    }
+
+    /// Writes `copies` copies of the test fixture's entries (with distinct
+    /// ids) to `path`, for benchmarks.
+    pub(crate) fn write_fixture_copies(path: &Path, copies: i64) {
+        let mut xml = String::new();
+        open_input("testdata/JMdict_e_test.gz")
+            .unwrap()
+            .reader
+            .read_to_string(&mut xml)
+            .unwrap();
+        let (head, rest) = xml.split_once("<entry>").unwrap();
+        let (entries, tail) = rest.rsplit_once("</entry>").unwrap();
+        let entries = (0..copies)
+            .map(|copy| {
+                entries
+                    .split("<ent_seq>")
+                    .enumerate()
+                    .map(|(i, part)| match part.split_once("</ent_seq>") {
+                        Some((ent_seq, rest)) if i > 0 => {
+                            let ent_seq = ent_seq.parse::<i64>().unwrap() + copy * 10_000_000;
+                            format!("<ent_seq>{ent_seq}</ent_seq>{rest}")
+                        }
+                        _ => part.to_string(),
+                    })
+                    .join("")
+            })
+            .join("</entry>\n<entry>");
+        write_gz(path, &format!("{head}<entry>{entries}</entry>{tail}"));
+    }
+
+    /// Times each stage of indexing copies of the test fixture on its own,
+    /// then the whole pipeline on one thread and on the default number of
+    /// threads. Run with
+    /// `cargo test --release bench_create_index -- --ignored --nocapture`.
+    ///
+    /// On 50 copies (5050 entries, release build, three runs) on a machine
+    /// with a single CPU, parsing took 320-510 ms, building documents 50 ms
+    /// and writing the index (which tokenizes) 370-460 ms; the pipeline took
+    /// 870-910 ms. So with a second core for the parser it could take about
+    /// half as long, and tantivy's writer threads split up the writing, while
+    /// building documents isn't worth threads. That machine couldn't show
+    /// the actual speed-up.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_create_index() {
+        const COPIES: i64 = 50;
+
+        let dir = tempfile::tempdir().unwrap();
+        let jmdict_path = dir.path().join("JMdict.gz");
+        write_fixture_copies(&jmdict_path, COPIES);
+        let jmdict_path = jmdict_path.to_str().unwrap();
+        let schema = create_schema();
+        let new_index = || {
+            let index = Index::create_in_ram(schema.clone());
+            register_tokenizers(&index).unwrap();
+            index
+        };
+
+        // The stages, one after the other: the parser thread's work, the
+        // workers' and the index writer's (which tokenizes)
+        let start = std::time::Instant::now();
+        let mut entries = Vec::new();
+        parse_jmdict(open_input(jmdict_path).unwrap().reader, false, |entry| {
+            entries.push(entry);
+            true
+        })
+        .unwrap();
+        let parsing = start.elapsed();
+        let start = std::time::Instant::now();
+        let documents = entries
+            .iter()
+            .flat_map(|entry| entry_documents(&schema, entry).1)
+            .collect::<Vec<_>>();
+        let building = start.elapsed();
+        let start = std::time::Instant::now();
+        let mut index_writer = new_index()
+            .writer_with_num_threads(1, DEFAULT_WRITER_MEMORY)
+            .unwrap();
+        for document in documents {
+            index_writer.add_document(document).unwrap();
+        }
+        index_writer.commit().unwrap();
+        let writing = start.elapsed();
+
+        let mut timings = Vec::new();
+        let mut thread_counts = vec![1, IndexOptions::default().threads];
+        thread_counts.dedup();
+        for threads in thread_counts {
+            let options = IndexOptions {
+                threads,
+                ..IndexOptions::default()
+            };
+            let start = std::time::Instant::now();
+            let changes =
+                create_index(&schema, jmdict_path, &new_index(), &options, &mut |_| {}).unwrap();
+            timings.push((threads, start.elapsed()));
+            assert_eq!(changes.added, 101 * usize::try_from(COPIES).unwrap());
+        }
+
+        println!("parsing:            {parsing:.2?}");
+        println!("building documents: {building:.2?}");
+        println!("writing the index:  {writing:.2?}");
+        for (threads, elapsed) in &timings {
+            println!("pipeline, {threads} thread(s): {elapsed:.2?}");
+        }
+        println!(
+            "pipeline with the parser on a core of its own, at best: {:.2?}",
+            parsing.max(building + writing)
+        );
+    }
+
+    #[test]
+    fn test_import_yomitan() {
+        let jmdict_path = Path::new("testdata/JMdict_e_test.gz");
+        let index_path = tempfile::tempdir().unwrap();
+        let schema = create_schema();
+        let index = Index::create_in_dir(index_path.path(), schema.clone()).unwrap();
+        register_tokenizers(&index).unwrap();
+
+        let dictionary =
+            yomitan::Dictionary::from_reader(std::io::Cursor::new(yomitan::test::test_archive()))
+                .unwrap();
+        import_yomitan(&schema, &dictionary, &index, &IndexOptions::default()).unwrap();
+        // Re-importing replaces the previous import
+        import_yomitan(&schema, &dictionary, &index, &IndexOptions::default()).unwrap();
+        // ...and re-indexing JMdict leaves it alone
+        create_index(
+            &schema,
+            jmdict_path.to_str().unwrap(),
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+
+        let source = schema.get_field("source").unwrap();
+        let reader = index.reader().unwrap();
+        let count = |name: &str| {
+            reader.reload().unwrap();
+            let query = tantivy::query::BooleanQuery::intersection(vec![
+                Box::new(TermQuery::new(
+                    Term::from_field_text(source, name),
+                    IndexRecordOption::Basic,
+                )),
+                Box::new(entries_query(&schema)),
+            ]);
+            let searcher = reader.searcher();
+            searcher.search(&query, &tantivy::collector::Count).unwrap()
+        };
+
+        // 2 terms, 3 meta entries and 1 kanji
+        assert_eq!(count("Test Dictionary"), 6);
+        assert_eq!(count(JMDICT_SOURCE), 101);
+
+        // Imported documents are searchable through the same fields as JMdict
+        let searcher = reader.searcher();
+        let word = schema.get_field("word").unwrap();
+        let query = tantivy::query::QueryParser::for_index(&index, vec![word])
+            .parse_query("錆びる")
+            .unwrap();
+        let hits = searcher
+            .search(&query, &tantivy::collector::TopDocs::with_limit(10))
+            .unwrap();
+        let entries = hits
+            .iter()
+            .map(|(_, address)| Entry::from_document(&schema, &searcher.doc(*address).unwrap()))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let imported = entries
+            .iter()
+            .find(|entry| entry.source == "Test Dictionary")
+            .unwrap();
+        assert_eq!(imported.headword(), "錆びる");
+        assert_eq!(imported.readings[0].text, "さびる");
+
+        // A dictionary titled "JMdict" neither replaces JMdict nor passes for it
+        let mut dictionary = dictionary;
+        dictionary.index.title = JMDICT_SOURCE.to_string();
+        import_yomitan(&schema, &dictionary, &index, &IndexOptions::default()).unwrap();
+        assert_eq!(count(JMDICT_SOURCE), 101);
+        assert_eq!(count("JMdict (Yomitan)"), 6);
+    }
+
+    #[test]
+    fn test_export_yomitan_round_trip() {
+        let jmdict_path = Path::new("testdata/JMdict_e_test.gz");
+        let schema = create_schema();
+        let index_path = tempfile::tempdir().unwrap();
+        let index = Index::create_in_dir(index_path.path(), schema.clone()).unwrap();
+        register_tokenizers(&index).unwrap();
+        create_index(
+            &schema,
+            jmdict_path.to_str().unwrap(),
+            &index,
+            &IndexOptions::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+
+        let exported = export_yomitan(&schema, &index, "akasabi").unwrap();
+        let archive = exported
+            .to_writer(std::io::Cursor::new(Vec::new()))
+            .unwrap();
+        let dictionary =
+            yomitan::Dictionary::from_reader(std::io::Cursor::new(archive.into_inner())).unwrap();
+
+        // Every entry survives, keyed by ent_seq
+        let sequences = dictionary.terms.iter().map(|t| t.sequence).unique().count();
+        assert_eq!(sequences, 101);
+
+        // Entity names are used as tags, with the descriptions in the tag bank
+        let akasabi = dictionary
+            .terms
+            .iter()
+            .find(|t| t.expression == "赤錆")
+            .unwrap();
+        assert_eq!(akasabi.reading, "あかさび");
+        assert_eq!(akasabi.sequence, 1_829_380);
+        assert_eq!(akasabi.definition_tags, vec!["n"]);
+        assert_eq!(akasabi.glossary, vec!["rust"]);
+        assert!(dictionary.tags.contains(&yomitan::Tag {
+            name: "n".into(),
+            category: "partOfSpeech".into(),
+            notes: "noun (common) (futsuumeishi)".into(),
+        }));
+        assert!(dictionary
+            .terms
+            .iter()
+            .all(|t| t.definition_tags.iter().all(|tag| !tag.contains(' '))));
+
+        // Importing the export gives back the original senses
+        let reimported_path = tempfile::tempdir().unwrap();
+        let reimported = Index::create_in_dir(reimported_path.path(), schema.clone()).unwrap();
+        register_tokenizers(&reimported).unwrap();
+        import_yomitan(&schema, &dictionary, &reimported, &IndexOptions::default()).unwrap();
+
+        let docs = |index: &Index| {
+            let searcher = index.reader().unwrap().searcher();
+            let word = schema.get_field("word").unwrap();
+            let query = tantivy::query::TermQuery::new(
+                Term::from_field_text(word, "赤錆"),
+                tantivy::schema::IndexRecordOption::Basic,
+            );
+            let hits = searcher
+                .search(&query, &tantivy::collector::TopDocs::with_limit(1))
+                .unwrap();
+            let doc = searcher.doc(hits[0].1).unwrap();
+            Entry::from_document(&schema, &doc).unwrap().senses
+        };
+        assert_eq!(docs(&index), docs(&reimported));
+    }
+
+    #[test]
+    fn test_index_meta_check() {
+        let schema = create_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let id = schema.get_field("id").unwrap();
+
+        // Empty indexes are always fine
+        IndexMeta::check(&index).unwrap();
+
+        // An index from before versioning has no version in its payload
+        let mut index_writer = index.writer(15_000_000).unwrap();
+        let mut document = tantivy::Document::default();
+        document.add_i64(id, 1);
+        index_writer.add_document(document).unwrap();
+        let mut prepared = index_writer.prepare_commit().unwrap();
+        prepared.set_payload(r#"{"entities": {}}"#);
+        prepared.commit().unwrap();
+        let error = IndexMeta::check(&index).unwrap_err();
+        assert!(error.is::<VersionMismatch>());
+        assert_eq!(
+            error.to_string(),
+            "index was built by an older akasabi, run `akasabi index` to rebuild it"
+        );
+
+        commit(&mut index_writer, &IndexMeta::default()).unwrap();
+        IndexMeta::check(&index).unwrap();
+        let meta = IndexMeta::load(&index).unwrap();
+        assert_eq!(meta.schema_version, SCHEMA_VERSION);
+        assert_eq!(meta.generator, env!("CARGO_PKG_VERSION"));
+
+        let newer = VersionMismatch {
+            schema_version: SCHEMA_VERSION + 1,
+            generator: "99.0.0".to_string(),
+        };
+        assert!(newer
+            .to_string()
+            .starts_with("index was built by a newer akasabi (v99.0.0)"));
     }
 }
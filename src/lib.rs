@@ -0,0 +1,28 @@
+//! Japanese-English dictionary search over a tantivy index of `JMdict`.
+//!
+//! ```no_run
+//! use akasabi::{Dictionary, SearchOptions};
+//!
+//! let dictionary = Dictionary::open("index")?;
+//! for result in dictionary.search("赤錆", &SearchOptions::new().limit(5))? {
+//!     println!("{} {:?}", result.entry.headword(), result.entry.senses);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+#![deny(clippy::all)]
+#![deny(clippy::pedantic)]
+// Everything here returns `anyhow::Result`; the error messages are for people
+#![allow(clippy::missing_errors_doc)]
+// The only panics are schema field lookups, which can't fail
+#![allow(clippy::missing_panics_doc)]
+
+mod dictionary;
+mod entry;
+pub mod indexer;
+mod query;
+mod staging;
+pub mod yomitan;
+
+pub use dictionary::{Dictionary, Field, SearchOptions, SearchResult, Sort, Tag};
+pub use entry::{Entry, KanjiForm, Reading, Reference, Sense};
+pub use query::QueryError;
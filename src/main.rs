@@ -1,24 +1,30 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
-use crate::indexer::create_index;
-use anyhow::{Context, Result};
+use akasabi::indexer;
+use akasabi::{Dictionary, Entry, SearchOptions, Tag};
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
 use etcetera::choose_app_strategy;
 use etcetera::AppStrategy;
 use etcetera::AppStrategyArgs;
-use itertools::izip;
 use itertools::Itertools;
 use std::clone::Clone;
-use std::fs::create_dir_all;
-use std::path::PathBuf;
-use tantivy::schema::Schema;
-use tantivy::{DocAddress, Document, Index, Score, Searcher};
-use yansi::{Color, Paint, Style};
-
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use yansi::Paint;
+
+mod anki;
+mod batch;
 mod config;
-mod indexer;
+mod diff;
+mod output;
+mod stats;
+mod subset;
+mod terminal;
 
-#[derive(clap::ValueEnum, Clone)]
+#[derive(clap::ValueEnum, Clone, Copy)]
 enum Field {
     Word,
     Reading,
@@ -26,6 +32,91 @@ enum Field {
     Meaning,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum Sort {
+    Relevance,
+    Id,
+    Reading,
+}
+
+impl From<Sort> for akasabi::Sort {
+    fn from(sort: Sort) -> Self {
+        match sort {
+            Sort::Relevance => akasabi::Sort::Relevance,
+            Sort::Id => akasabi::Sort::Id,
+            Sort::Reading => akasabi::Sort::Reading,
+        }
+    }
+}
+
+/// Filters on sense tags, by `JMdict` code (eg. v5r) or description.
+#[derive(clap::Args)]
+struct Filters {
+    #[clap(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        value_name = "POS",
+        help = "Only senses with this part of speech, eg. v5r; -v5r leaves out entries with it"
+    )]
+    pos: Vec<String>,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        value_name = "DOMAIN",
+        help = "Only senses in this field of use, eg. comp; -comp leaves out entries with it"
+    )]
+    domain: Vec<String>,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        value_name = "MISC",
+        help = "Only senses with this misc tag, eg. on-mim; -on-mim leaves out entries with it"
+    )]
+    misc: Vec<String>,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        value_name = "DIALECT",
+        help = "Only senses from this dialect, eg. ksb; -ksb leaves out entries with it"
+    )]
+    dial: Vec<String>,
+}
+
+impl Filters {
+    fn apply(&self, mut options: SearchOptions) -> SearchOptions {
+        let filters = [
+            (Tag::Pos, &self.pos),
+            (Tag::Field, &self.domain),
+            (Tag::Misc, &self.misc),
+            (Tag::Dialect, &self.dial),
+        ];
+        for (tag, values) in filters {
+            for value in values {
+                options = match value.strip_prefix('-') {
+                    Some(value) => options.exclude(tag, value),
+                    None => options.filter(tag, value),
+                };
+            }
+        }
+        options
+    }
+}
+
+impl From<Field> for akasabi::Field {
+    fn from(field: Field) -> Self {
+        match field {
+            Field::Word => akasabi::Field::Word,
+            Field::Reading => akasabi::Field::Reading,
+            Field::ReadingRomaji => akasabi::Field::ReadingRomaji,
+            Field::Meaning => akasabi::Field::Meaning,
+        }
+    }
+}
+
 #[derive(clap::ValueEnum, Clone, Debug)]
 enum ColorArg {
     Auto,
@@ -42,6 +133,8 @@ struct Args {
     index: Option<PathBuf>,
     #[clap(long, global = true, default_value = "auto")]
     color: ColorArg,
+    #[clap(long, global = true, help = "Don't page long output through $PAGER")]
+    no_pager: bool,
     #[command(subcommand)]
     command: Command,
 }
@@ -49,17 +142,58 @@ struct Args {
 #[derive(Subcommand)]
 enum Command {
     Search {
+        #[clap(
+            allow_hyphen_values = true,
+            help = "Words to search for; k:, r:, m:, p: and f: limit a word to the kanji, reading, meaning, part of speech or field, -word excludes, a|b matches either"
+        )]
+        #[clap(default_value = "")]
         term: String,
         #[clap(short, long)]
         field: Option<Field>,
+        #[clap(long, help = "Search for the term as it is, without any query syntax")]
+        literal: bool,
+        #[command(flatten)]
+        filters: Filters,
+        #[clap(short = 'n', long, default_value_t = 10, help = "Entries per page")]
+        limit: usize,
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        page: u64,
+        #[clap(
+            long,
+            help = "Order of the results [default: relevance, or id without a term]"
+        )]
+        sort: Option<Sort>,
         #[clap(short = 'm', long)]
         create_if_missing: bool,
+        #[clap(
+            short = 'e',
+            long,
+            help = "Only print the senses that matched, like myougiden -e"
+        )]
+        matching_senses: bool,
+        #[command(flatten)]
+        output: output::Args,
+        #[clap(
+            long,
+            alias = "anki-connect",
+            value_name = "URL",
+            help = "Add the best match to Anki through AnkiConnect, at URL if given"
+        )]
+        #[allow(clippy::option_option)] // flag with an optional value
+        add_to_anki: Option<Option<String>>,
+    },
+    /// Show entries by their id, as printed by search --show-id
+    Show {
+        #[clap(required = true)]
+        ids: Vec<i64>,
+        #[command(flatten)]
+        output: output::Args,
     },
     Index {
         #[clap(
             short,
             long,
-            help = "Path to JMdict.gz file",
+            help = "Path to the JMdict file (plain XML, or gzip, xz, zstd or bzip2 compressed), or - for stdin",
             default_value = "JMdict_e.gz"
         )]
         path: String,
@@ -69,8 +203,97 @@ enum Command {
             help = "Automatically download the latest JMdict.gz file if it doesn't exist"
         )]
         jmdict_url: Option<String>,
+        #[clap(
+            long,
+            help = "Skip entries that can't be parsed instead of failing, and list them afterwards"
+        )]
+        lenient: bool,
+        #[clap(
+            long,
+            help = "Number of index writer threads [default: number of CPUs]"
+        )]
+        threads: Option<usize>,
+        #[clap(long, help = "Index writer memory budget in megabytes [default: 50]")]
+        writer_memory_mb: Option<usize>,
+    },
+    /// Import additional dictionaries into the index
+    Import {
+        #[clap(long, help = "Path to a Yomitan/Yomichan dictionary zip")]
+        yomitan: PathBuf,
+    },
+    /// Export the index to other formats
+    Export {
+        #[clap(long, help = "Write a Yomitan dictionary zip to this path")]
+        yomitan: PathBuf,
+        #[clap(
+            long,
+            help = "Dictionary title shown in Yomitan",
+            default_value = "akasabi"
+        )]
+        title: String,
+    },
+    /// Make Anki flashcards from entries
+    Anki {
+        #[command(subcommand)]
+        command: AnkiCommand,
+    },
+    /// Look up a list of words and write the best matches as TSV or CSV
+    Batch {
+        #[clap(
+            help = "File with one word per line, or - for stdin",
+            default_value = "-"
+        )]
+        input: PathBuf,
+        #[clap(short, long, help = "Write to this file instead of stdout")]
+        output: Option<PathBuf>,
+        #[clap(long, default_value = "tsv")]
+        format: batch::Format,
+        #[clap(
+            long,
+            value_delimiter = ',',
+            default_value = "input,kanji,reading,glosses,pos,id,confidence"
+        )]
+        columns: Vec<batch::Column>,
+        #[clap(long, help = "Number of glosses to include", default_value_t = 3)]
+        glosses: usize,
+    },
+    Info {
+        #[clap(long, help = "Print as JSON")]
+        json: bool,
+    },
+    /// Compare two dictionary releases, or two index directories, entry by entry
+    Diff {
+        #[clap(help = "Old JMdict file (or - for stdin) or index directory")]
+        old: PathBuf,
+        #[clap(help = "New JMdict file or index directory")]
+        new: PathBuf,
+        #[clap(long, default_value = "text")]
+        format: diff::Format,
+        #[clap(short, long, help = "Write to this file instead of stdout")]
+        output: Option<PathBuf>,
+    },
+    /// Write a smaller copy of a dictionary file with only some of its entries, eg. for test fixtures
+    Subset {
+        #[clap(help = "JMdict file, or - for stdin")]
+        input: String,
+        #[clap(long, value_delimiter = ',', help = "Keep the entries with these ids")]
+        ids: Vec<i64>,
+        #[clap(long, help = "Keep the best matches of this search (can be repeated)")]
+        query: Vec<String>,
+        #[clap(long, help = "Keep the first N entries", default_value_t = 0)]
+        first: usize,
+        #[clap(
+            short,
+            long,
+            help = "Write to this file instead of stdout, compressed according to its extension"
+        )]
+        output: Option<PathBuf>,
+    },
+    /// Show statistics about the index and the dictionary entries in it
+    Stats {
+        #[clap(long, help = "Print as JSON")]
+        json: bool,
     },
-    Info,
     // Primarily for debugging
     PrintConfig {
         // index is already a global option
@@ -81,6 +304,34 @@ enum Command {
     },
 }
 
+#[derive(Subcommand)]
+enum AnkiCommand {
+    /// Write entries to an Anki deck package (.apkg)
+    Export {
+        #[clap(help = "Search terms; the best match for each is added")]
+        queries: Vec<String>,
+        #[clap(long = "id", help = "Add the entry with this ent_seq")]
+        ids: Vec<i64>,
+        #[clap(short, long, help = "File with one search term per line")]
+        word_list: Option<PathBuf>,
+        #[clap(
+            short,
+            long,
+            help = "Path to write the .apkg file to",
+            required_unless_present = "anki_connect"
+        )]
+        output: Option<PathBuf>,
+        #[clap(
+            long,
+            value_name = "URL",
+            conflicts_with = "output",
+            help = "Add the notes to a running Anki through AnkiConnect instead, at URL if given"
+        )]
+        #[allow(clippy::option_option)] // flag with an optional value
+        anki_connect: Option<Option<String>>,
+    },
+}
+
 // TODO: Refactor this into multiple functions
 #[allow(clippy::too_many_lines)]
 fn main() -> Result<()> {
@@ -110,9 +361,7 @@ fn main() -> Result<()> {
         let color = match args.color {
             ColorArg::Auto => {
                 nix::unistd::isatty(nix::libc::STDOUT_FILENO).unwrap_or(false)
-                    && !std::env::var("NO_COLOR")
-                        .map(|s| s.is_empty())
-                        .unwrap_or(true)
+                    && std::env::var("NO_COLOR").map_or(true, |s| s.is_empty())
             }
             ColorArg::Always => true,
             ColorArg::Never => false,
@@ -135,49 +384,338 @@ fn main() -> Result<()> {
         .or(config_index_path)
         .map_or_else(|| strategy.in_data_dir("index"), Clone::clone);
 
-    let schema = indexer::create_schema();
-
-    let index = if index_path.join("meta.json").exists() {
-        Index::open_in_dir(&index_path).context("Failed to open index")?
-    } else {
-        create_dir_all(index_path.clone()).context("Failed to create index directory")?;
-        Index::create_in_dir(&index_path, schema.clone()).context("Failed to create index")?
-    };
+    let open = || open_dictionary(&index_path, &config);
 
     match args.command {
         Command::Search {
             term,
             field,
+            literal,
+            filters,
+            limit,
+            page,
+            sort,
             create_if_missing: _,
+            matching_senses,
+            output,
+            add_to_anki,
+        } => {
+            let dictionary = open()?;
+            let browsing = term.trim().is_empty();
+            let default_sort = if browsing { Sort::Id } else { Sort::Relevance };
+            let mut options = filters.apply(
+                SearchOptions::new()
+                    .literal(literal)
+                    // One more, to tell whether there's another page
+                    .limit(limit.saturating_add(1))
+                    .offset(limit.saturating_mul(usize::try_from(page - 1)?))
+                    .sort(sort.unwrap_or(default_sort).into()),
+            );
+            if let Some(field) = field {
+                options = options.field(field.into());
+            }
+            let mut results = dictionary.search(&term, &options)?;
+            let more = results.len() > limit;
+            results.truncate(limit);
+
+            let size = terminal::size();
+            let print_options = output::PrintOptions {
+                matching_senses,
+                ..print_options(&output, &config, size, &dictionary)?
+            };
+            let mut out = terminal::Pager::new(size.filter(|_| !args.no_pager));
+            for result in &results {
+                output::print_entry(
+                    &mut out,
+                    &result.entry,
+                    Some(&result.senses),
+                    &print_options,
+                )?;
+                if output.follow_xrefs {
+                    output::print_references(
+                        &mut out,
+                        &dictionary,
+                        &result.entry,
+                        Some(&result.senses),
+                        &print_options,
+                    )?;
+                }
+            }
+            out.finish()?;
+            if size.is_some() && more {
+                let hint = format!("More results with --page {}", page + 1);
+                eprintln!("{}", Paint::default(hint).dimmed());
+            }
+
+            if let Some(url) = add_to_anki {
+                let settings = anki::Settings::from_config(&config.anki);
+                match results.into_iter().next() {
+                    Some(best) => add_to_anki_connect(
+                        &settings,
+                        url.as_deref().unwrap_or(&settings.connect_url),
+                        &[best.entry],
+                    )?,
+                    None => eprintln!("{} nothing to add to Anki", Paint::yellow("warning:")),
+                }
+            }
+        }
+        Command::Show { ids, output } => {
+            let dictionary = open()?;
+            let size = terminal::size();
+            let print_options = print_options(&output, &config, size, &dictionary)?;
+            let mut out = terminal::Pager::new(size.filter(|_| !args.no_pager));
+            let mut missing = Vec::new();
+            for id in ids {
+                match dictionary.get(id)? {
+                    Some(entry) => {
+                        output::print_entry(&mut out, &entry, None, &print_options)?;
+                        if output.follow_xrefs {
+                            output::print_references(
+                                &mut out,
+                                &dictionary,
+                                &entry,
+                                None,
+                                &print_options,
+                            )?;
+                        }
+                    }
+                    None => missing.push(id),
+                }
+            }
+            out.finish()?;
+            if !missing.is_empty() {
+                bail!("No entry with id {}", missing.iter().join(", "));
+            }
+        }
+        Command::Index {
+            path,
+            lenient,
+            threads,
+            writer_memory_mb,
+            ..
         } => {
-            let (searcher, top_docs) = search(&index, &schema, &term, &field)?;
+            let options = indexer::IndexOptions {
+                lenient,
+                ..index_options(&config, threads, writer_memory_mb)
+            };
+            let changes = Dictionary::index(&index_path, &path, &options, print_progress)?;
+            println!("and committed: {changes}.");
+            report_changes(&changes);
+        }
+        Command::Import { yomitan } => {
+            let dictionary = akasabi::yomitan::Dictionary::from_file(&yomitan)?;
+            let count = open()?.import_yomitan(&dictionary, &index_options(&config, None, None))?;
+            println!(
+                "{} entries imported from {} (revision {})",
+                Paint::default(count).bold(),
+                Paint::default(indexer::import_source(&dictionary.index.title)).bold(),
+                dictionary.index.revision
+            );
+        }
+        Command::Export { yomitan, title } => {
+            let dictionary = open()?.export_yomitan(&title)?;
+            dictionary.to_file(&yomitan)?;
+            println!(
+                "{} terms, {} term meta and {} kanji written to {}",
+                Paint::default(dictionary.terms.len()).bold(),
+                Paint::default(dictionary.term_meta.len()).bold(),
+                Paint::default(dictionary.kanji.len()).bold(),
+                yomitan.display()
+            );
+        }
+        Command::Anki {
+            command:
+                AnkiCommand::Export {
+                    mut queries,
+                    ids,
+                    word_list,
+                    output,
+                    anki_connect,
+                },
+        } => {
+            if let Some(word_list) = word_list {
+                queries.extend(read_word_list(&word_list)?);
+            }
 
-            for (_score, doc_address) in top_docs {
-                let retrieved_doc = searcher.doc(doc_address)?;
-                print_result(&schema, &retrieved_doc, &term);
+            let settings = anki::Settings::from_config(&config.anki);
+            let entries = collect_entries(&open()?, &queries, &ids)?;
+
+            if let Some(url) = anki_connect {
+                let url = url.as_deref().unwrap_or(&settings.connect_url);
+                add_to_anki_connect(&settings, url, &entries)?;
+            } else if let Some(output) = output {
+                let notes = entries
+                    .iter()
+                    .map(|entry| anki::Note::from_entry(entry, &settings))
+                    .unique_by(|note| note.guid.clone())
+                    .collect_vec();
+                anki::write_apkg(&output, &settings, &notes)?;
+                println!(
+                    "{} notes written to {}",
+                    Paint::default(notes.len()).bold(),
+                    output.display()
+                );
             }
         }
-        Command::Index { path, .. } => {
-            index_(&index, &schema, &path)?;
+        Command::Batch {
+            input,
+            output,
+            format,
+            columns,
+            glosses,
+        } => {
+            let dictionary = open()?;
+            let words = read_word_list(&input)?;
+            let output = create_output(output.as_deref())?;
+            let mut writer = batch::Writer::new(output, format, columns, glosses);
+            writer.write_header()?;
+
+            let (mut unmatched, mut ambiguous) = (Vec::new(), Vec::new());
+            for word in &words {
+                match batch::lookup(&dictionary, word)? {
+                    Some(lookup) => {
+                        writer.write_lookup(&lookup)?;
+                        if lookup.confidence == batch::Confidence::Ambiguous {
+                            ambiguous.push(lookup);
+                        }
+                    }
+                    None => unmatched.push(word),
+                }
+            }
+
+            eprintln!(
+                "{} of {} words matched",
+                Paint::default(words.len() - unmatched.len()).bold(),
+                words.len()
+            );
+            if !unmatched.is_empty() {
+                eprintln!("{}", Paint::yellow("No match:"));
+                for word in unmatched {
+                    eprintln!("  {word}");
+                }
+            }
+            if !ambiguous.is_empty() {
+                eprintln!("{}", Paint::yellow("Ambiguous (first candidate used):"));
+                for lookup in ambiguous {
+                    eprintln!(
+                        "  {} (ent_seq {})",
+                        lookup.input,
+                        lookup.candidates.iter().join(", ")
+                    );
+                }
+            }
         }
-        Command::Info => {
-            // Print program info; ie version and configuration (currently only resolved index path)
+        Command::Info { json } => {
+            // Print program info; ie version, configuration and where the indexed JMdict came from
+            // FIXME: This is duplicated from above
+            let config_path = args
+                .config
+                .unwrap_or_else(|| strategy.in_config_dir("config.toml"));
+            // Without opening the index, which fails or rebuilds it if it's
+            // incompatible, when where it came from is most useful to know
+            let jmdict = indexer::IndexMeta::read(&index_path)?.and_then(|meta| meta.jmdict);
+            if json {
+                let info = serde_json::json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "index_path": index_path,
+                    "config_path": config_path,
+                    "jmdict": jmdict,
+                });
+                println!("{}", serde_json::to_string_pretty(&info)?);
+                return Ok(());
+            }
+
             println!(
                 "akasabi {}{}",
                 Paint::green("v"),
                 Paint::green(env!("CARGO_PKG_VERSION"))
             );
             println!("Index path: {}", Paint::blue(index_path.to_str().unwrap()));
-            {
-                // FIXME: This is duplicated from above
-                let config_path = args
-                    .config
-                    .unwrap_or_else(|| strategy.in_config_dir("config.toml"));
-                println!(
-                    "Config path: {}",
-                    Paint::blue(config_path.to_str().unwrap())
+            println!(
+                "Config path: {}",
+                Paint::blue(config_path.to_str().unwrap())
+            );
+            match jmdict {
+                Some(jmdict) => {
+                    println!(
+                        "JMdict: created {}, {} entries",
+                        Paint::green(jmdict.created.as_deref().unwrap_or("(unknown)")),
+                        Paint::default(jmdict.entries).bold()
+                    );
+                    println!("  source: {}", Paint::blue(&jmdict.source));
+                    println!("  sha256: {}", jmdict.sha256);
+                    if let Some(indexed_at) = &jmdict.indexed_at {
+                        println!("  indexed at: {indexed_at}");
+                    }
+                }
+                None => println!("JMdict: not indexed"),
+            }
+            println!("Config: {config:#?}");
+        }
+        Command::Diff {
+            old,
+            new,
+            format,
+            output,
+        } => {
+            let (old, old_jmdict) = read_entries(&old)?;
+            let (new, new_jmdict) = read_entries(&new)?;
+            let changes = diff::Diff {
+                old_jmdict,
+                new_jmdict,
+                ..diff::diff(&old, &new)
+            };
+            let mut output = create_output(output.as_deref())?;
+            changes.write(&mut output, format)?;
+            output.flush()?;
+        }
+        Command::Subset {
+            input,
+            ids,
+            query,
+            first,
+            output,
+        } => {
+            let mut selection = subset::Selection {
+                ids: ids.into_iter().collect(),
+                first,
+            };
+            if !query.is_empty() {
+                let dictionary = open()?;
+                for term in &query {
+                    let results = dictionary.search(term, &SearchOptions::new())?;
+                    selection
+                        .ids
+                        .extend(results.into_iter().filter_map(|r| r.entry.id));
+                }
+            }
+
+            let subset = subset::subset(&input, &selection)?;
+            if !subset.missing.is_empty() {
+                eprintln!(
+                    "{} not found: {}",
+                    Paint::yellow("warning:"),
+                    subset.missing.iter().join(", ")
                 );
-                println!("Config: {config:#?}");
+            }
+            match output {
+                Some(path) => {
+                    indexer::write_compressed(&path, subset.xml.as_bytes())?;
+                    println!(
+                        "{} entries written to {}",
+                        Paint::default(subset.entries).bold(),
+                        path.display()
+                    );
+                }
+                None => print!("{}", subset.xml),
+            }
+        }
+        Command::Stats { json } => {
+            let stats = stats::collect(&open()?)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print!("{stats}");
             }
         }
         Command::PrintConfig {
@@ -187,11 +725,14 @@ fn main() -> Result<()> {
             let string = config::Config {
                 index: config::Index {
                     path: Some(index_path),
+                    ..config.index
                 },
                 jmdict: config::Jmdict {
                     path: jmdict_path.or(config.jmdict.path),
                     url: jmdict_url.or(config.jmdict.url),
                 },
+                output: config.output,
+                anki: config.anki,
             }
             .to_str()?;
             print!("{string}");
@@ -201,126 +742,196 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn index_(index: &Index, schema: &Schema, path: &str) -> Result<()> {
-    create_index(schema, path, index)?;
-    Ok(())
+/// Output options from the command line, falling back to the config, for
+/// a terminal of the given size.
+fn print_options(
+    args: &output::Args,
+    config: &config::Config,
+    size: Option<(usize, usize)>,
+    dictionary: &Dictionary,
+) -> Result<output::PrintOptions> {
+    let abbreviations = match args.tags.or(config.output.tags).unwrap_or_default() {
+        output::Tags::Expanded => HashMap::new(),
+        output::Tags::Abbreviated => dictionary
+            .meta()?
+            .entities
+            .into_iter()
+            .map(|(code, description)| (description, code))
+            .collect(),
+    };
+    Ok(output::PrintOptions {
+        format: args.format.or(config.output.format).unwrap_or_default(),
+        columns: args
+            .columns
+            .clone()
+            .or_else(|| config.output.columns.clone())
+            .unwrap_or_else(|| output::DEFAULT_COLUMNS.to_vec()),
+        matching_senses: false,
+        show_id: args.show_id,
+        width: size.map(|(_, columns)| columns),
+        abbreviations,
+    })
 }
 
-fn search(
-    index: &Index,
-    schema: &Schema,
-    term: &str,
-    field: &Option<Field>,
-) -> Result<(Searcher, Vec<(Score, DocAddress)>)> {
-    let (word, reading, reading_romaji, meaning) = (
-        schema.get_field("word").unwrap(),
-        schema.get_field("reading").unwrap(),
-        schema.get_field("reading_romaji").unwrap(),
-        schema.get_field("meaning").unwrap(),
-    );
-
-    let reader = index
-        .reader_builder()
-        .reload_policy(tantivy::ReloadPolicy::Manual)
-        .try_into()?;
-    let searcher = reader.searcher();
-
-    let fields = match field {
-        Some(Field::Word) => vec![word],
-        Some(Field::Reading) => vec![reading],
-        Some(Field::ReadingRomaji) => vec![reading_romaji],
-        Some(Field::Meaning) => vec![meaning],
-        None => vec![word, reading, reading_romaji, meaning],
-    };
+/// Indexing options from the command line, falling back to the config.
+fn index_options(
+    config: &config::Config,
+    threads: Option<usize>,
+    writer_memory_mb: Option<usize>,
+) -> indexer::IndexOptions {
+    let defaults = indexer::IndexOptions::default();
+    indexer::IndexOptions {
+        threads: threads.or(config.index.threads).unwrap_or(defaults.threads),
+        writer_memory: writer_memory_mb
+            .or(config.index.writer_memory_mb)
+            .map_or(defaults.writer_memory, |mb| mb * 1_000_000),
+        ..defaults
+    }
+}
 
-    let mut query_parser = tantivy::query::QueryParser::for_index(index, fields);
-    query_parser.set_conjunction_by_default();
+/// Prints how far `akasabi index` has got.
+fn print_progress(progress: indexer::Progress) {
+    match progress {
+        indexer::Progress::Read(count) => {
+            println!("{} entries read...", Paint::default(count).bold());
+        }
+        indexer::Progress::Committing(count) => {
+            print!("{} entries read... ", Paint::default(count).bold());
+            // Flush stdout so that the progress indicator is displayed
+            std::io::stdout().flush().ok();
+        }
+    }
+}
 
-    let query = query_parser.parse_query(term)?;
+/// Warns about anything that went wrong while indexing.
+fn report_changes(changes: &indexer::Changes) {
+    let warning = Paint::yellow("warning:");
+    if changes.rebuilt {
+        eprintln!(
+            "{warning} replaced the incompatible existing index; imported dictionaries will need to be imported again"
+        );
+    }
+    if !changes.skipped.is_empty() {
+        eprintln!("{warning} {} entries skipped:", changes.skipped.len());
+        for error in &changes.skipped {
+            eprintln!("  {error}");
+        }
+    }
+    if let Some(error) = &changes.truncated {
+        eprintln!(
+            "{warning} stopped reading at {error}; later entries were not indexed, and no entries were removed"
+        );
+    }
+}
 
-    let top_docs = searcher.search(&query, &tantivy::collector::TopDocs::with_limit(10))?;
+/// Opens the index, rebuilding it if it's incompatible and we know where
+/// `JMdict` is.
+fn open_dictionary(index_path: &Path, config: &config::Config) -> Result<Dictionary> {
+    match Dictionary::open(index_path) {
+        Ok(dictionary) => Ok(dictionary),
+        Err(e) if e.is::<indexer::VersionMismatch>() => match &config.jmdict.path {
+            Some(source) if source.exists() => {
+                eprintln!("{} {e}", Paint::yellow("warning:"));
+                eprintln!("Rebuilding index from {}", source.display());
+                let changes = Dictionary::index(
+                    index_path,
+                    &source.to_string_lossy(),
+                    &index_options(config, None, None),
+                    |_| {},
+                )?;
+                eprintln!("Rebuilt the index: {changes}");
+                report_changes(&changes);
+                Dictionary::open(index_path)
+            }
+            _ => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
 
-    Ok((searcher, top_docs))
+/// Creates the output file, or writes to stdout if there is none.
+fn create_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?))
+        }
+        None => Box::new(std::io::stdout().lock()),
+    })
 }
 
-// TODO: Also take query so we can highlight it
-fn print_result(schema: &Schema, document: &Document, _term: &str) {
-    // entry fields
-    let word = schema.get_field("word").unwrap();
-    let reading = schema.get_field("reading").unwrap();
-    // let reading_romaji = schema.get_field("reading_romaji").unwrap();
-
-    // sense fields
-    let meaning = schema.get_field("meaning").unwrap();
-    let pos = schema.get_field("pos").unwrap();
-    let field = schema.get_field("field").unwrap();
-
-    // myougiden format:
-    // kanji [;kanji]* (reading [、reading]*)*
-    // 1. \[poc\] meaning [; meaning]*
-    // 2. \[field\] meaning [; meaning]*
-
-    let kanji = document
-        .get_all(word)
-        .map(|f| f.as_text().unwrap())
-        .collect_vec();
-    let readings = document
-        .get_all(reading)
-        .map(|f| f.as_text().unwrap())
-        .collect_vec();
-
-    // meanings, pos, and fields should be "aligned" (ie. same length, n-th element of each)
-    let meanings = document
-        .get_all(meaning)
-        .map(|f| f.as_text().unwrap())
-        .collect_vec();
-    let pos = document
-        .get_all(pos)
-        .map(|f| f.as_text().unwrap())
-        .collect_vec();
-    let fields = document
-        .get_all(field)
-        .map(|f| f.as_text().unwrap())
-        .collect_vec();
-
-    let c_kanji = Style::new(Color::Blue).bold();
-    let c_reading = Style::new(Color::Magenta).bold();
-
-    // field shares style with pos
-    let c_pos = Style::new(Color::Yellow).bold();
-    let c_meaning = Style::new(Color::Default).bold();
-    // let c_highlight = Style::new(Color::Red).bold();
-    let c_index = Style::new(Color::Green).bold();
-
-    if kanji.is_empty() {
-        println!("{}", c_kanji.paint(readings.join("、")));
+/// Reads `JMdict` entries from a file, or from an index if `path` is one,
+/// and where they came from.
+fn read_entries(path: &Path) -> Result<(BTreeMap<i64, Entry>, Option<indexer::Provenance>)> {
+    if path.join("meta.json").exists() {
+        let dictionary = Dictionary::open(path)
+            .with_context(|| format!("Failed to open index {}", path.display()))?;
+        let entries = dictionary
+            .entries()?
+            .into_iter()
+            .filter(Entry::is_jmdict)
+            .filter_map(|entry| Some((entry.id?, entry)))
+            .collect();
+        Ok((entries, dictionary.meta()?.jmdict))
     } else {
-        println!(
-            "{} ({})",
-            // TODO: Style separator separately
-            c_kanji.paint(kanji.join("; ")),
-            c_reading.paint(readings.join("、"))
-        );
+        let (entries, provenance) = indexer::read_entries(&path.to_string_lossy())?;
+        Ok((entries, Some(provenance)))
+    }
+}
+
+/// Reads a word list from a file, or stdin for `-`.
+fn read_word_list(path: &Path) -> Result<Vec<String>> {
+    if path == Path::new("-") {
+        batch::read_words(std::io::stdin().lock())
+    } else {
+        let file =
+            File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        batch::read_words(BufReader::new(file))
     }
+}
 
-    for (idx, (meaning, pos, field)) in izip!(meanings, pos, fields).enumerate() {
-        let meanings = meaning.split("; ").collect_vec();
+/// Resolves search terms (to their best match) and ids to entries, warning
+/// about anything that couldn't be found.
+fn collect_entries(dictionary: &Dictionary, queries: &[String], ids: &[i64]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for query in queries {
+        match dictionary
+            .search(query, &SearchOptions::new().limit(1))?
+            .into_iter()
+            .next()
+        {
+            Some(best) => entries.push(best.entry),
+            None => eprintln!("{} no match for {query}", Paint::yellow("warning:")),
+        }
+    }
+    for &id in ids {
+        match dictionary.get(id)? {
+            Some(entry) => entries.push(entry),
+            None => eprintln!("{} no entry with id {id}", Paint::yellow("warning:")),
+        }
+    }
+    Ok(entries)
+}
 
-        // TODO: Properly handle pos and field (split and re-join)
-        print!(
-            "{} [{};{}]",
-            c_index.paint(format!("{}.", idx + 1)),
-            c_pos.paint(pos),
-            c_pos.paint(field)
-        );
-        for (idx, meaning) in meanings.iter().enumerate() {
-            if idx == 0 {
-                print!(" {}", c_meaning.paint(meaning));
-                continue;
+fn add_to_anki_connect(settings: &anki::Settings, url: &str, entries: &[Entry]) -> Result<()> {
+    let connect = anki::AnkiConnect::new(url);
+    connect.prepare(settings)?;
+
+    for entry in entries {
+        let note = anki::Note::from_entry(entry, settings);
+        let name = note.fields.first().cloned().unwrap_or_default();
+        match connect.add_note(settings, &note)? {
+            anki::AddResult::Added(id) => {
+                println!("{} {name} (note {id})", Paint::green("Added"));
             }
-            print!("{}{}", Paint::yellow("; "), c_meaning.paint(meaning));
+            anki::AddResult::Duplicate(ids) => println!(
+                "{} {name}, already in {} (note {})",
+                Paint::yellow("Skipped"),
+                settings.deck,
+                ids.iter().join(", ")
+            ),
         }
-        println!();
     }
-    println!();
+    Ok(())
 }
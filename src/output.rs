@@ -0,0 +1,471 @@
+//! Printing entries, for `search` and `show`.
+
+use crate::terminal;
+use akasabi::{Dictionary, Entry, Sense};
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use yansi::{Color, Paint, Style};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Format {
+    /// An entry per block, with every sense on its own line
+    #[default]
+    Full,
+    /// An entry per line: headword, reading and the first glosses
+    Compact,
+    /// An entry per line, as tab-separated columns without colors
+    Tsv,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Column {
+    Id,
+    Headword,
+    Kanji,
+    Reading,
+    Glosses,
+    Pos,
+    Field,
+    Misc,
+    Dial,
+    Source,
+}
+
+/// How part of speech, field and misc tags are shown.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Tags {
+    /// As described in `JMdict`, eg. "noun (common) (futsuumeishi)"
+    #[default]
+    Expanded,
+    /// As `JMdict` entity names, eg. "n"
+    Abbreviated,
+}
+
+pub(crate) const DEFAULT_COLUMNS: [Column; 5] = [
+    Column::Id,
+    Column::Headword,
+    Column::Reading,
+    Column::Glosses,
+    Column::Pos,
+];
+
+/// Number of glosses shown by `Format::Compact`
+const COMPACT_GLOSSES: usize = 3;
+
+/// Output options shared by `search` and `show`.
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    #[clap(
+        long,
+        help = "Output format [default: full, or output.format in the config]"
+    )]
+    pub(crate) format: Option<Format>,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Columns of --format tsv [default: id,headword,reading,glosses,pos, or output.columns in the config]"
+    )]
+    pub(crate) columns: Option<Vec<Column>>,
+    #[clap(
+        long,
+        help = "How to show part of speech, field and misc tags [default: expanded, or output.tags in the config]"
+    )]
+    pub(crate) tags: Option<Tags>,
+    #[clap(long, help = "Print each entry's id (its ent_seq in JMdict)")]
+    pub(crate) show_id: bool,
+    #[clap(
+        long,
+        help = "Also print the entries they refer to (see also, antonyms)"
+    )]
+    pub(crate) follow_xrefs: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PrintOptions {
+    pub(crate) format: Format,
+    /// Columns of `Format::Tsv`
+    pub(crate) columns: Vec<Column>,
+    /// Leave out senses that didn't match the search
+    pub(crate) matching_senses: bool,
+    pub(crate) show_id: bool,
+    /// Terminal width to wrap senses to
+    pub(crate) width: Option<usize>,
+    /// Entity names by description, to abbreviate tags with
+    pub(crate) abbreviations: HashMap<String, String>,
+}
+
+impl PrintOptions {
+    /// A sense's tags as they're to be shown, eg. "n, vs".
+    fn tags(&self, tags: &[String]) -> String {
+        if self.abbreviations.is_empty() {
+            return tags.join("; ");
+        }
+        tags.iter()
+            .map(|tag| self.abbreviations.get(tag).unwrap_or(tag))
+            .join(",")
+    }
+
+    fn wrap(&self, line: &str, indent: usize) -> String {
+        match self.width {
+            Some(width) => terminal::wrap(line, width, indent),
+            None => line.to_string(),
+        }
+    }
+
+    /// The senses of `entry` to print, with their positions.
+    fn senses<'a>(
+        &self,
+        entry: &'a Entry,
+        matched: Option<&'a [usize]>,
+    ) -> impl Iterator<Item = (usize, &'a Sense)> {
+        let matching_senses = self.matching_senses;
+        entry.senses.iter().enumerate().filter(move |(idx, _)| {
+            !matching_senses || matched.is_none_or(|matched| matched.contains(idx))
+        })
+    }
+}
+
+/// Prints an entry; `matched` are the senses that matched the search, best
+/// first, if it came from one.
+pub(crate) fn print_entry(
+    out: &mut impl Write,
+    entry: &Entry,
+    matched: Option<&[usize]>,
+    options: &PrintOptions,
+) -> Result<()> {
+    match options.format {
+        Format::Full => print_full(out, entry, matched, options)?,
+        Format::Compact => writeln!(out, "{}", compact_line(entry, matched, options))?,
+        Format::Tsv => writeln!(out, "{}", tsv_line(entry, matched, options))?,
+    }
+    Ok(())
+}
+
+// TODO: Also take query so we can highlight it
+fn print_full(
+    out: &mut impl Write,
+    entry: &Entry,
+    matched: Option<&[usize]>,
+    options: &PrintOptions,
+) -> Result<()> {
+    // myougiden format:
+    // kanji [;kanji]* (reading [、reading]*)*
+    // 1. \[pos\] {field} (misc, dialect) meaning [; meaning]*
+    // 2. {field} (misc, dialect) meaning [; meaning]*
+
+    // Only point out the best sense if the search told them apart
+    let best_sense = matched
+        .filter(|matched| matched.len() < entry.senses.len())
+        .and_then(|matched| matched.first().copied());
+
+    let kanji = entry.kanji.iter().map(|k| k.text.as_str()).collect_vec();
+    let readings = entry.readings.iter().map(|r| r.text.as_str()).collect_vec();
+
+    let c_kanji = Style::new(Color::Blue).bold();
+    let c_reading = Style::new(Color::Magenta).bold();
+
+    // field shares style with pos
+    let c_pos = Style::new(Color::Yellow).bold();
+    let c_meaning = Style::new(Color::Default).bold();
+    let c_highlight = Style::new(Color::Red).bold();
+    let c_index = Style::new(Color::Green).bold();
+    let c_source = Style::new(Color::Cyan);
+    let c_id = Style::new(Color::Default).dimmed();
+    let c_reference = Style::new(Color::Cyan);
+
+    if kanji.is_empty() {
+        write!(out, "{}", c_kanji.paint(readings.join("、")))?;
+    } else if readings.is_empty() {
+        // Only happens for imported frequency lists without readings
+        write!(out, "{}", c_kanji.paint(kanji.join("; ")))?;
+    } else {
+        write!(
+            out,
+            "{} ({})",
+            // TODO: Style separator separately
+            c_kanji.paint(kanji.join("; ")),
+            c_reading.paint(readings.join("、"))
+        )?;
+    }
+    if let Some(id) = entry.id.filter(|_| options.show_id) {
+        write!(out, " {}", c_id.paint(format!("#{id}")))?;
+    }
+    // Only call out the source for imported dictionaries
+    if entry.is_jmdict() {
+        writeln!(out)?;
+    } else {
+        writeln!(out, " {}", c_source.paint(format!("[{}]", entry.source)))?;
+    }
+
+    if let Some(freq) = entry.frequency {
+        writeln!(out, "{} {}", c_pos.paint("frequency:"), freq)?;
+    }
+    if !entry.pitch.is_empty() {
+        let pitches = entry.pitch.iter().map(|p| format!("[{p}]")).join(" ");
+        writeln!(out, "{} {}", c_pos.paint("pitch accent:"), pitches)?;
+    }
+
+    let mut last_pos = None;
+    for (idx, sense) in options.senses(entry, matched) {
+        let index = if best_sense == Some(idx) {
+            format!("{}.*", idx + 1)
+        } else {
+            format!("{}.", idx + 1)
+        };
+        // Continuation lines line up with the text after the sense number
+        let indent = index.len() + 1;
+        let index = if best_sense == Some(idx) {
+            c_highlight.paint(index)
+        } else {
+            c_index.paint(index)
+        };
+        let mut tags = Vec::new();
+        // Like JMdict, only give the part of speech when it changes
+        if !sense.pos.is_empty() && last_pos != Some(&sense.pos) {
+            tags.push(format!("[{}]", options.tags(&sense.pos)));
+        }
+        last_pos = Some(&sense.pos);
+        if !sense.field.is_empty() {
+            tags.push(format!("{{{}}}", options.tags(&sense.field)));
+        }
+        let misc = [sense.misc.as_slice(), &sense.dial].concat();
+        if !misc.is_empty() {
+            tags.push(format!("({})", options.tags(&misc)));
+        }
+        let glosses = sense
+            .glosses
+            .iter()
+            .map(|gloss| c_meaning.paint(gloss))
+            .join(&Paint::yellow("; ").to_string());
+        let line = std::iter::once(index.to_string())
+            .chain(tags.into_iter().map(|tag| c_pos.paint(tag).to_string()))
+            .chain([glosses])
+            .join(" ");
+        writeln!(out, "{}", options.wrap(&line, indent))?;
+        for (label, references) in [("See also:", &sense.xref), ("Antonym:", &sense.ant)] {
+            if !references.is_empty() {
+                let line = format!(
+                    "{:indent$}{} {}",
+                    "",
+                    c_reference.paint(label),
+                    references.iter().join(", ")
+                );
+                writeln!(out, "{}", options.wrap(&line, indent))?;
+            }
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Prints the entries that the printed senses of `entry` refer to, with the
+/// referenced sense marked, and warns about references that can't be resolved.
+pub(crate) fn print_references(
+    out: &mut impl Write,
+    dictionary: &Dictionary,
+    entry: &Entry,
+    matched: Option<&[usize]>,
+    options: &PrintOptions,
+) -> Result<()> {
+    let c_reference = Style::new(Color::Cyan);
+
+    let references = options
+        .senses(entry, matched)
+        .flat_map(|(_, sense)| {
+            let see_also = sense.xref.iter().map(|r| ("See also:", r));
+            see_also.chain(sense.ant.iter().map(|r| ("Antonym:", r)))
+        })
+        .unique_by(|(_, reference)| *reference);
+    for (label, reference) in references {
+        match dictionary.resolve(reference)? {
+            Some(target) => {
+                if options.format == Format::Full {
+                    let label = c_reference.paint(format!("→ {label}"));
+                    writeln!(out, "{label} {reference}")?;
+                }
+                let sense = reference.sense.map(|sense| vec![sense - 1]);
+                let options = PrintOptions {
+                    matching_senses: false,
+                    ..options.clone()
+                };
+                print_entry(out, &target, sense.as_deref(), &options)?;
+            }
+            None => eprintln!(
+                "{} can't find {reference}, referred to by {}",
+                Paint::yellow("warning:"),
+                entry.headword()
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn readings(entry: &Entry) -> String {
+    entry.readings.iter().map(|r| &r.text).join("、")
+}
+
+/// Headword, readings and the first glosses of the senses to print, eg.
+/// "赤錆 (あかさび) rust".
+fn compact_line(entry: &Entry, matched: Option<&[usize]>, options: &PrintOptions) -> String {
+    let c_kanji = Style::new(Color::Blue).bold();
+    let c_reading = Style::new(Color::Magenta).bold();
+    let c_id = Style::new(Color::Default).dimmed();
+
+    let mut parts = vec![c_kanji.paint(entry.headword()).to_string()];
+    if !entry.kanji.is_empty() && !entry.readings.is_empty() {
+        parts.push(format!("({})", c_reading.paint(readings(entry))));
+    }
+    if let Some(id) = entry.id.filter(|_| options.show_id) {
+        parts.push(c_id.paint(format!("#{id}")).to_string());
+    }
+    let glosses = options
+        .senses(entry, matched)
+        .flat_map(|(_, sense)| &sense.glosses)
+        .take(COMPACT_GLOSSES)
+        .join("; ");
+    if !glosses.is_empty() {
+        parts.push(glosses);
+    }
+    parts.join(" ")
+}
+
+/// The configured columns, with tabs and newlines in values replaced by spaces.
+fn tsv_line(entry: &Entry, matched: Option<&[usize]>, options: &PrintOptions) -> String {
+    let senses = options.senses(entry, matched).map(|(_, s)| s).collect_vec();
+    let tags = |tags: fn(&Sense) -> &Vec<String>| {
+        let tags = senses
+            .iter()
+            .flat_map(|s| tags(s))
+            .unique()
+            .cloned()
+            .collect_vec();
+        options.tags(&tags)
+    };
+    options
+        .columns
+        .iter()
+        .map(|column| match column {
+            Column::Id => entry.id.map(|id| id.to_string()).unwrap_or_default(),
+            Column::Headword => entry.headword().to_string(),
+            Column::Kanji => entry.kanji.iter().map(|k| &k.text).join("; "),
+            Column::Reading => readings(entry),
+            Column::Glosses => senses.iter().flat_map(|s| &s.glosses).join("; "),
+            Column::Pos => tags(|s| &s.pos),
+            Column::Field => tags(|s| &s.field),
+            Column::Misc => tags(|s| &s.misc),
+            Column::Dial => tags(|s| &s.dial),
+            Column::Source => entry.source.clone(),
+        })
+        .map(|value| value.replace(['\t', '\n', '\r'], " "))
+        .join("\t")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use akasabi::{KanjiForm, Reading};
+
+    fn entry() -> Entry {
+        let sense = |glosses: &[&str], pos: &str| Sense {
+            glosses: glosses.iter().map(ToString::to_string).collect(),
+            pos: vec![pos.to_string()],
+            ..Sense::default()
+        };
+        Entry {
+            id: Some(1_829_380),
+            source: "JMdict".into(),
+            kanji: vec![KanjiForm {
+                text: "赤錆".into(),
+            }],
+            readings: vec![Reading {
+                text: "あかさび".into(),
+            }],
+            senses: vec![
+                sense(&["rust", "red\trust"], "noun"),
+                sense(&["corrosion"], "noun"),
+            ],
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn test_tsv_line() {
+        let mut options = PrintOptions {
+            format: Format::Tsv,
+            columns: DEFAULT_COLUMNS.to_vec(),
+            ..PrintOptions::default()
+        };
+        assert_eq!(
+            tsv_line(&entry(), None, &options),
+            "1829380\t赤錆\tあかさび\trust; red rust; corrosion\tnoun"
+        );
+
+        options.columns = vec![Column::Glosses, Column::Source];
+        options.matching_senses = true;
+        assert_eq!(
+            tsv_line(&entry(), Some(&[1]), &options),
+            "corrosion\tJMdict"
+        );
+    }
+
+    #[test]
+    fn test_print_full() {
+        let mut entry = entry();
+        entry.senses.push(Sense {
+            glosses: vec!["to rust".into()],
+            pos: vec!["suru verb".into()],
+            misc: vec!["usually written using kana alone".into()],
+            ..Sense::default()
+        });
+        let mut options = PrintOptions::default();
+        let plain = |options: &PrintOptions| {
+            let mut out = Vec::new();
+            print_full(&mut out, &entry, None, options).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            // Leave out colors
+            let mut text = String::new();
+            let mut chars = out.chars();
+            while let Some(c) = chars.next() {
+                if c == '\x1b' {
+                    chars.by_ref().find(char::is_ascii_alphabetic);
+                } else {
+                    text.push(c);
+                }
+            }
+            text
+        };
+        assert_eq!(
+            plain(&options),
+            "赤錆 (あかさび)\n\
+             1. [noun] rust; red\trust\n\
+             2. corrosion\n\
+             3. [suru verb] (usually written using kana alone) to rust\n\n"
+        );
+
+        options.abbreviations = HashMap::from([
+            ("noun".into(), "n".into()),
+            ("suru verb".into(), "vs".into()),
+            ("usually written using kana alone".into(), "uk".into()),
+        ]);
+        assert!(plain(&options).ends_with("3. [vs] (uk) to rust\n\n"));
+    }
+
+    #[test]
+    fn test_compact_line() {
+        let options = PrintOptions {
+            format: Format::Compact,
+            show_id: true,
+            ..PrintOptions::default()
+        };
+        let line = compact_line(&entry(), None, &options);
+        assert!(line.contains("赤錆"));
+        assert!(line.contains("#1829380"));
+        assert!(line.ends_with(" rust; red\trust; corrosion"));
+    }
+}
@@ -0,0 +1,352 @@
+//! akasabi's query language, parsed into tantivy queries.
+//!
+//! A query is a list of terms, all of which have to match:
+//!
+//! - `k:` (kanji), `r:` (reading), `m:` (meaning), `p:` (part of speech)
+//!   and `f:` (field) limit a term to one field, eg. `m:rust`
+//! - `"..."` quotes a term with spaces or special characters, eg. `m:"to be"`
+//! - `a|b` matches either of the terms
+//! - `-` in front of a term (or `a|b`) excludes entries that match it in any
+//!   of their senses
+//!
+//! Anything else is searched for as it is, so `10:30` or `C++` don't need
+//! quoting. A query without terms to search for, eg. only `-k:錆`, matches
+//! every entry that isn't excluded.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use tantivy::query::{AllQuery, BooleanQuery, PhraseQuery, Query, TermQuery};
+use tantivy::schema::IndexRecordOption;
+use tantivy::Index;
+
+/// Field prefixes, and the index fields they search.
+const PREFIXES: &[(&str, &str, &[&str])] = &[
+    ("k", "kanji", &["word"]),
+    ("r", "reading", &["reading", "reading_romaji"]),
+    ("m", "meaning", &["meaning"]),
+    ("p", "pos", &["pos"]),
+    ("f", "field", &["field"]),
+];
+
+/// A query that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    pub message: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid query: {}", self.message)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+fn error<T>(message: impl Into<String>) -> Result<T, QueryError> {
+    Err(QueryError {
+        message: message.into(),
+    })
+}
+
+/// Text to look for, in the fields of its prefix or else the default ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Term {
+    fields: Option<&'static [&'static str]>,
+    text: String,
+}
+
+/// Any of `terms`, which has to match, or not match if `exclude` is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Clause {
+    exclude: bool,
+    terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParsedQuery {
+    clauses: Vec<Clause>,
+}
+
+/// Parses the query language described in the module docs.
+pub(crate) fn parse(query: &str) -> Result<ParsedQuery, QueryError> {
+    let mut clauses = Vec::new();
+    let mut chars = query.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+        if first == '|' {
+            return error("\"|\" needs a term on both sides, eg. m:rust|m:corrosion");
+        }
+        let exclude = chars.next_if_eq(&'-').is_some();
+        let mut terms = vec![parse_term(&mut chars, exclude)?];
+        // More alternatives, allowing spaces around "|"
+        loop {
+            let mut lookahead = chars.clone();
+            while lookahead.next_if(|c| c.is_whitespace()).is_some() {}
+            if lookahead.next_if_eq(&'|').is_none() {
+                break;
+            }
+            while lookahead.next_if(|c| c.is_whitespace()).is_some() {}
+            chars = lookahead;
+            terms.push(parse_term(&mut chars, false)?);
+        }
+        clauses.push(Clause { exclude, terms });
+    }
+    Ok(ParsedQuery { clauses })
+}
+
+/// Parses `prefix:text` or `prefix:"text"`, where the prefix is optional.
+fn parse_term(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    exclude: bool,
+) -> Result<Term, QueryError> {
+    let mut text = String::new();
+    let mut fields = None;
+    loop {
+        match chars.peek() {
+            None => break,
+            Some(c) if c.is_whitespace() || *c == '|' => break,
+            Some('"') if text.is_empty() => {
+                chars.next();
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => quoted.push(c),
+                        None => return error(format!("missing the closing quote of \"{quoted}")),
+                    }
+                }
+                if quoted.trim().is_empty() {
+                    return error("empty quotes");
+                }
+                return Ok(Term {
+                    fields,
+                    text: quoted,
+                });
+            }
+            Some(':') if fields.is_none() && is_prefix(&text) => {
+                chars.next();
+                fields = Some(prefix_fields(&text)?);
+                text.clear();
+            }
+            Some(&c) => {
+                chars.next();
+                text.push(c);
+            }
+        }
+    }
+    if text.is_empty() {
+        let message = match (fields, exclude) {
+            (Some(_), _) => "a field prefix needs a term after it, eg. m:rust",
+            (None, true) => "\"-\" needs a term after it, eg. -m:rust",
+            (None, false) => "\"|\" needs a term on both sides, eg. m:rust|m:corrosion",
+        };
+        return error(message);
+    }
+    Ok(Term { fields, text })
+}
+
+/// Whether `text` looks like it's meant as a field prefix.
+fn is_prefix(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+fn prefix_fields(prefix: &str) -> Result<&'static [&'static str], QueryError> {
+    let prefix = prefix.to_lowercase();
+    match PREFIXES
+        .iter()
+        .find(|(short, long, _)| prefix == *short || prefix == *long)
+    {
+        Some((_, _, fields)) => Ok(fields),
+        None => error(format!(
+            "unknown field \"{prefix}:\", use k: (kanji), r: (reading), m: (meaning), \
+             p: (part of speech) or f: (field), or quote the term"
+        )),
+    }
+}
+
+/// Takes the whole of `query` as one term, without any special characters.
+pub(crate) fn literal(query: &str) -> ParsedQuery {
+    let terms = vec![Term {
+        fields: None,
+        text: query.to_string(),
+    }];
+    let clauses = if query.trim().is_empty() {
+        Vec::new()
+    } else {
+        vec![Clause {
+            exclude: false,
+            terms,
+        }]
+    };
+    ParsedQuery { clauses }
+}
+
+/// The tantivy queries for a query. Each sense is its own document, so the
+/// excluded terms are a query of their own, to leave out whole entries.
+pub(crate) struct Queries {
+    /// Senses with all the terms that have to match
+    pub(crate) matching: Box<dyn Query>,
+    /// Senses with any of the excluded terms, if there are any
+    pub(crate) excluded: Option<Box<dyn Query>>,
+}
+
+impl ParsedQuery {
+    /// Whether any term is limited to the part of speech or field.
+    pub(crate) fn has_tags(&self) -> bool {
+        self.clauses
+            .iter()
+            .flat_map(|clause| &clause.terms)
+            .any(|term| {
+                term.fields
+                    .is_some_and(|fields| fields == ["pos"] || fields == ["field"])
+            })
+    }
+
+    /// Builds the tantivy queries, searching `default_fields` for terms
+    /// without a prefix. Queries without terms to search for match
+    /// everything. `entities` lets `p:` and `f:` take `JMdict`'s entity names,
+    /// as the index has their descriptions.
+    pub(crate) fn to_tantivy(
+        &self,
+        index: &Index,
+        default_fields: &[&str],
+        entities: &BTreeMap<String, String>,
+    ) -> Result<Queries> {
+        let mut clauses = Vec::new();
+        let mut excluded = Vec::new();
+        for clause in &self.clauses {
+            let mut alternatives = Vec::new();
+            for term in &clause.terms {
+                let fields = term.fields.unwrap_or(default_fields);
+                let tags = fields == ["pos"] || fields == ["field"];
+                let text = match entities.get(&term.text) {
+                    Some(description) if tags => description,
+                    _ => &term.text,
+                };
+                for field in fields {
+                    alternatives.extend(field_query(index, field, text)?);
+                }
+            }
+            if clause.exclude {
+                // Nothing in it that's indexed, eg. only punctuation, excludes nothing
+                excluded.extend(alternatives);
+                continue;
+            }
+            match alternatives.len() {
+                // Nothing in it that's indexed, eg. only punctuation
+                0 => {
+                    let text = clause.terms.iter().map(|term| term.text.as_str());
+                    return Err(QueryError {
+                        message: format!(
+                            "nothing searchable in \"{}\"",
+                            text.collect::<Vec<_>>().join("|")
+                        ),
+                    }
+                    .into());
+                }
+                1 => clauses.push(alternatives.remove(0)),
+                _ => clauses.push(Box::new(BooleanQuery::union(alternatives))),
+            }
+        }
+        if clauses.is_empty() {
+            clauses.push(Box::new(AllQuery));
+        }
+        let excluded = (!excluded.is_empty())
+            .then(|| Box::new(BooleanQuery::union(excluded)) as Box<dyn Query>);
+        Ok(Queries {
+            matching: Box::new(BooleanQuery::intersection(clauses)),
+            excluded,
+        })
+    }
+}
+
+/// A query for `text` in one field, tokenized the way the field is indexed:
+/// a phrase query if that gives several tokens. `None` if it gives none.
+fn field_query(index: &Index, name: &str, text: &str) -> Result<Option<Box<dyn Query>>> {
+    let field = index.schema().get_field(name)?;
+    let mut tokenizer = index.tokenizer_for_field(field)?;
+    let mut terms = Vec::new();
+    tokenizer.token_stream(text).process(&mut |token| {
+        terms.push((
+            token.position,
+            tantivy::Term::from_field_text(field, &token.text),
+        ));
+    });
+    Ok(match terms.len() {
+        0 => None,
+        1 => Some(Box::new(TermQuery::new(
+            terms.remove(0).1,
+            IndexRecordOption::WithFreqs,
+        ))),
+        _ => Some(Box::new(PhraseQuery::new_with_offset(terms))),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn term(fields: Option<&'static [&'static str]>, text: &str) -> Term {
+        Term {
+            fields,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let query = parse("m:rust -k:錆 r:あか|r:aka").unwrap();
+        assert_eq!(
+            query.clauses,
+            vec![
+                Clause {
+                    exclude: false,
+                    terms: vec![term(Some(&["meaning"]), "rust")],
+                },
+                Clause {
+                    exclude: true,
+                    terms: vec![term(Some(&["word"]), "錆")],
+                },
+                Clause {
+                    exclude: false,
+                    terms: vec![
+                        term(Some(&["reading", "reading_romaji"]), "あか"),
+                        term(Some(&["reading", "reading_romaji"]), "aka"),
+                    ],
+                },
+            ]
+        );
+
+        // Spaces around "|", quotes, and characters that used to be syntax
+        let query = parse("meaning:\"to be\" | t-shirt  10:30 C++ (x) !").unwrap();
+        assert_eq!(
+            query.clauses[0].terms,
+            vec![term(Some(&["meaning"]), "to be"), term(None, "t-shirt")]
+        );
+        let texts = query.clauses[1..].iter().map(|c| c.terms[0].text.as_str());
+        assert_eq!(texts.collect::<Vec<_>>(), vec!["10:30", "C++", "(x)", "!"]);
+        assert!(!query.has_tags());
+        assert!(parse("p:v5r").unwrap().has_tags());
+        assert_eq!(parse(" ").unwrap().clauses, Vec::new());
+        assert!(parse("-rust").unwrap().clauses[0].exclude);
+
+        let message = |query| parse(query).unwrap_err().to_string();
+        assert!(message("x:rust").contains("unknown field \"x:\""));
+        assert!(message("m:\"to be").contains("closing quote"));
+        assert!(message("rust|").contains("both sides"));
+        assert!(message("| rust").contains("both sides"));
+        assert!(message("m: rust").contains("needs a term after it"));
+        assert!(message("rust - m:red").contains("\"-\" needs a term"));
+    }
+
+    #[test]
+    fn test_literal() {
+        let query = literal("m:\"C++\" -x");
+        assert_eq!(query.clauses[0].terms, vec![term(None, "m:\"C++\" -x")]);
+        assert_eq!(literal(" ").clauses, Vec::new());
+    }
+}
@@ -0,0 +1,234 @@
+//! Rebuilding the index next to the live one, and swapping it in once it's
+//! complete, so searches never see a half-built index.
+
+use crate::indexer::{self, IndexMeta};
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use tantivy::collector::Count;
+use tantivy::query::{BooleanQuery, TermQuery};
+use tantivy::schema::{IndexRecordOption, Schema};
+use tantivy::{Index, Term};
+
+/// Held while the index is being modified, so two indexers can't run at once.
+pub(crate) struct IndexLock {
+    _file: File,
+}
+
+/// Returns `<index>.<suffix>`, next to the index.
+fn sibling(index_path: &Path, suffix: &str) -> PathBuf {
+    let mut name = index_path
+        .file_name()
+        .map_or_else(|| OsString::from("index"), ToOwned::to_owned);
+    name.push(".");
+    name.push(suffix);
+    index_path.with_file_name(name)
+}
+
+pub(crate) fn staging_path(index_path: &Path) -> PathBuf {
+    sibling(index_path, "staging")
+}
+
+/// Takes the lock for modifying the index at `index_path`, failing if someone
+/// else holds it. The lock is released when the returned value is dropped.
+pub(crate) fn lock(index_path: &Path) -> Result<IndexLock> {
+    let path = sibling(index_path, "lock");
+    let file = File::create(&path)
+        .with_context(|| format!("Failed to create lock file {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(IndexLock { _file: file }),
+        Err(fs::TryLockError::WouldBlock) => bail!(
+            "Another akasabi is already modifying the index (lock held on {})",
+            path.display()
+        ),
+        Err(fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+        }
+    }
+}
+
+/// Creates a fresh staging index next to the live one. If `copy_existing` is
+/// set, it starts out as a copy of the live index, so re-indexing only has to
+/// apply what changed and imported dictionaries are kept.
+pub(crate) fn prepare(index_path: &Path, schema: &Schema, copy_existing: bool) -> Result<Index> {
+    let staging = staging_path(index_path);
+    // Left over from an indexer that died
+    if staging.exists() {
+        fs::remove_dir_all(&staging)
+            .with_context(|| format!("Failed to remove {}", staging.display()))?;
+    }
+    fs::create_dir_all(&staging)
+        .with_context(|| format!("Failed to create {}", staging.display()))?;
+
+    if copy_existing && index_path.join("meta.json").exists() {
+        for entry in fs::read_dir(index_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            // Lock files belong to whoever has the live index open
+            if !entry.file_type()?.is_file() || name.to_string_lossy().ends_with(".lock") {
+                continue;
+            }
+            // tantivy never modifies files in place, so sharing them is safe
+            let target = staging.join(&name);
+            if fs::hard_link(entry.path(), &target).is_err() {
+                fs::copy(entry.path(), &target)?;
+            }
+        }
+        Index::open_in_dir(&staging).context("Failed to open staging index")
+    } else {
+        Index::create_in_dir(&staging, schema.clone()).context("Failed to create staging index")
+    }
+}
+
+/// Number of `JMdict` entries in `index`.
+pub(crate) fn jmdict_entries(index: &Index, schema: &Schema) -> Result<usize> {
+    let query = BooleanQuery::intersection(vec![
+        Box::new(TermQuery::new(
+            Term::from_field_text(schema.get_field("source").unwrap(), indexer::JMDICT_SOURCE),
+            IndexRecordOption::Basic,
+        )),
+        Box::new(indexer::entries_query(schema)),
+    ]);
+    Ok(index.reader()?.searcher().search(&query, &Count)?)
+}
+
+/// Checks that the staging index is complete before it's swapped in.
+pub(crate) fn verify(index_path: &Path, schema: &Schema, expected_entries: usize) -> Result<()> {
+    let staging = staging_path(index_path);
+    let index = Index::open_in_dir(&staging).context("Failed to open staging index")?;
+    IndexMeta::check(&index)?;
+
+    let entries = jmdict_entries(&index, schema)?;
+    if entries != expected_entries {
+        bail!(
+            "The new index has {entries} JMdict entries instead of {expected_entries}; \
+             keeping the current index (the new one is left in {})",
+            staging.display()
+        );
+    }
+    Ok(())
+}
+
+/// Swaps the staging index in for the live one, and removes the old one.
+pub(crate) fn swap(index_path: &Path) -> Result<()> {
+    let staging = staging_path(index_path);
+    exchange(&staging, index_path).with_context(|| {
+        format!(
+            "Failed to move {} into place at {}",
+            staging.display(),
+            index_path.display()
+        )
+    })?;
+    // Nothing to remove if there was no live index yet
+    if !staging.exists() {
+        return Ok(());
+    }
+    // Searches that still have the old index open keep their files until they exit
+    fs::remove_dir_all(&staging)
+        .with_context(|| format!("Failed to remove the old index in {}", staging.display()))
+}
+
+/// Atomically exchanges two directories.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn exchange(a: &Path, b: &Path) -> Result<()> {
+    use nix::fcntl::{renameat2, RenameFlags};
+
+    if b.exists() {
+        renameat2(None, a, None, b, RenameFlags::RENAME_EXCHANGE)?;
+    } else {
+        fs::rename(a, b)?;
+    }
+    Ok(())
+}
+
+/// Exchanges two directories. Not atomic: `b` is briefly missing.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn exchange(a: &Path, b: &Path) -> Result<()> {
+    let temporary = sibling(b, "old");
+    if b.exists() {
+        fs::rename(b, &temporary)?;
+    }
+    fs::rename(a, b)?;
+    if temporary.exists() {
+        fs::rename(&temporary, a)?;
+    } else {
+        fs::create_dir(a)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_path = dir.path().join("index");
+
+        let held = lock(&index_path).unwrap();
+        let error = lock(&index_path).err().unwrap();
+        assert!(error.to_string().starts_with("Another akasabi"));
+
+        drop(held);
+        lock(&index_path).unwrap();
+    }
+
+    #[test]
+    fn test_stage_and_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_path = dir.path().join("index");
+        let schema = indexer::create_schema();
+        let source = schema.get_field("source").unwrap();
+        let sense = schema.get_field("sense").unwrap();
+
+        fs::create_dir(&index_path).unwrap();
+        let live = Index::create_in_dir(&index_path, schema.clone()).unwrap();
+        let mut writer = live.writer(15_000_000).unwrap();
+        let mut document = tantivy::Document::default();
+        document.add_text(source, "Test Dictionary");
+        document.add_u64(sense, 0);
+        writer.add_document(document).unwrap();
+        writer.commit().unwrap();
+        let live_searcher = live.reader().unwrap().searcher();
+
+        // The staging index starts out as a copy of the live one
+        let staging = prepare(&index_path, &schema, true).unwrap();
+        assert_eq!(staging.reader().unwrap().searcher().num_docs(), 1);
+        let mut writer = staging.writer(15_000_000).unwrap();
+        let mut document = tantivy::Document::default();
+        document.add_text(source, indexer::JMDICT_SOURCE);
+        document.add_u64(sense, 0);
+        writer.add_document(document).unwrap();
+        writer.commit().unwrap();
+        drop(writer);
+
+        // Committed without a schema version, so it's not swapped in
+        assert!(verify(&index_path, &schema, 1).is_err());
+        // Wrong entry count
+        let mut writer = staging.writer(15_000_000).unwrap();
+        indexer::test::commit_meta(&mut writer);
+        drop(writer);
+        assert!(verify(&index_path, &schema, 2).is_err());
+        verify(&index_path, &schema, 1).unwrap();
+
+        swap(&index_path).unwrap();
+        assert!(!staging_path(&index_path).exists());
+        let swapped = Index::open_in_dir(&index_path).unwrap();
+        assert_eq!(swapped.reader().unwrap().searcher().num_docs(), 2);
+
+        // Searches that had the old index open still work
+        assert_eq!(live_searcher.num_docs(), 1);
+        let query = tantivy::query::TermQuery::new(
+            Term::from_field_text(source, "Test Dictionary"),
+            tantivy::schema::IndexRecordOption::Basic,
+        );
+        assert_eq!(
+            live_searcher
+                .search(&query, &tantivy::collector::Count)
+                .unwrap(),
+            1
+        );
+    }
+}
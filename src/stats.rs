@@ -0,0 +1,183 @@
+//! Statistics about the index and the `JMdict` entries in it, for sanity
+//! checking new releases and spotting index bloat.
+
+use akasabi::indexer::Provenance;
+use akasabi::Dictionary;
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use yansi::Paint;
+
+/// Number of misc tags shown by `Display`
+const TOP_MISC: usize = 10;
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Stats {
+    /// Where the indexed `JMdict` came from
+    pub(crate) jmdict: Option<Provenance>,
+    /// All documents, one per sense, including imported dictionaries
+    pub(crate) documents: u64,
+    /// `JMdict` entries
+    pub(crate) entries: usize,
+    pub(crate) with_kanji: usize,
+    pub(crate) without_kanji: usize,
+    pub(crate) senses: usize,
+    /// How many senses have each value
+    pub(crate) pos: BTreeMap<String, usize>,
+    pub(crate) field: BTreeMap<String, usize>,
+    pub(crate) misc: BTreeMap<String, usize>,
+    pub(crate) segments: Vec<Segment>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Segment {
+    pub(crate) id: String,
+    pub(crate) documents: u32,
+    pub(crate) deleted: u32,
+    pub(crate) bytes: u64,
+}
+
+pub(crate) fn collect(dictionary: &Dictionary) -> Result<Stats> {
+    let mut stats = Stats {
+        jmdict: dictionary.meta()?.jmdict,
+        ..Stats::default()
+    };
+    for entry in dictionary.entries()?.iter().filter(|e| e.is_jmdict()) {
+        stats.entries += 1;
+        if entry.kanji.is_empty() {
+            stats.without_kanji += 1;
+        } else {
+            stats.with_kanji += 1;
+        }
+        stats.senses += entry.senses.len();
+        for sense in &entry.senses {
+            for (tags, histogram) in [
+                (&sense.pos, &mut stats.pos),
+                (&sense.field, &mut stats.field),
+                (&sense.misc, &mut stats.misc),
+            ] {
+                for tag in tags {
+                    *histogram.entry(tag.clone()).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let searcher = dictionary.tantivy_index().reader()?.searcher();
+    stats.documents = searcher.num_docs();
+    for segment_reader in searcher.segment_readers() {
+        stats.segments.push(Segment {
+            id: segment_reader.segment_id().uuid_string(),
+            documents: segment_reader.num_docs(),
+            deleted: segment_reader.num_deleted_docs(),
+            bytes: segment_reader.space_usage()?.total().get_bytes(),
+        });
+    }
+    Ok(stats)
+}
+
+/// Values by descending count, then by name.
+fn by_count(histogram: &BTreeMap<String, usize>) -> impl Iterator<Item = (&String, &usize)> {
+    histogram
+        .iter()
+        .sorted_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)))
+}
+
+fn kilobytes(bytes: u64) -> String {
+    format!("{} kB", bytes.div_ceil(1000))
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Documents: {}", Paint::default(self.documents).bold())?;
+        writeln!(
+            f,
+            "JMdict entries: {} ({} with kanji, {} without)",
+            Paint::default(self.entries).bold(),
+            self.with_kanji,
+            self.without_kanji
+        )?;
+        writeln!(f, "Senses: {}", Paint::default(self.senses).bold())?;
+        if let Some(jmdict) = &self.jmdict {
+            writeln!(
+                f,
+                "JMdict: created {}, sha256 {}",
+                jmdict.created.as_deref().unwrap_or("(unknown)"),
+                jmdict.sha256
+            )?;
+        }
+
+        for (title, histogram, limit) in [
+            ("Parts of speech", &self.pos, usize::MAX),
+            ("Fields", &self.field, usize::MAX),
+            ("Most common misc tags", &self.misc, TOP_MISC),
+        ] {
+            writeln!(f, "\n{title}:")?;
+            for (value, count) in by_count(histogram).take(limit) {
+                writeln!(f, "{count:>8}  {value}")?;
+            }
+        }
+
+        let bytes: u64 = self.segments.iter().map(|s| s.bytes).sum();
+        writeln!(
+            f,
+            "\nSegments: {} ({})",
+            Paint::default(self.segments.len()).bold(),
+            kilobytes(bytes)
+        )?;
+        for segment in &self.segments {
+            writeln!(
+                f,
+                "  {}  {} documents, {} deleted, {}",
+                segment.id,
+                segment.documents,
+                segment.deleted,
+                kilobytes(segment.bytes)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use akasabi::indexer::IndexOptions;
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        Dictionary::index(
+            dir.path(),
+            "testdata/JMdict_e_test.gz",
+            &IndexOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let dictionary = Dictionary::open(dir.path()).unwrap();
+
+        let stats = collect(&dictionary).unwrap();
+        assert_eq!(stats.documents, 151);
+        assert_eq!(stats.entries, 101);
+        assert_eq!(stats.with_kanji + stats.without_kanji, 101);
+        assert!(stats.without_kanji > 0);
+        assert!(stats.senses > stats.entries);
+        assert!(stats.pos.contains_key("noun (common) (futsuumeishi)"));
+        assert!(!stats.segments.is_empty());
+        assert!(stats.segments.iter().all(|s| s.bytes > 0));
+        assert_eq!(
+            stats
+                .segments
+                .iter()
+                .map(|s| u64::from(s.documents))
+                .sum::<u64>(),
+            stats.documents
+        );
+
+        let text = stats.to_string();
+        assert!(text.contains("noun (common) (futsuumeishi)"));
+        assert!(text.contains("Most common misc tags:"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Cutting a smaller `JMdict` out of a full one, eg. for test fixtures.
+//!
+//! Entries are copied verbatim, so entity references like `&n;` are kept, and
+//! so is everything before the first entry (the DTD and the creation date).
+
+use akasabi::indexer;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::io::Read;
+
+const ENTRY_START: &str = "<entry>";
+const ENTRY_END: &str = "</entry>";
+
+/// Which entries to keep; an entry is kept if any of these select it.
+#[derive(Debug, Default)]
+pub(crate) struct Selection {
+    pub(crate) ids: BTreeSet<i64>,
+    /// Keep the first N entries of the file
+    pub(crate) first: usize,
+}
+
+/// The smaller `JMdict`, and the selected ids that weren't found.
+pub(crate) struct Subset {
+    pub(crate) xml: String,
+    pub(crate) entries: usize,
+    pub(crate) missing: Vec<i64>,
+}
+
+fn ent_seq(entry: &str) -> Option<i64> {
+    let start = entry.find("<ent_seq>")? + "<ent_seq>".len();
+    let end = start + entry[start..].find("</ent_seq>")?;
+    entry[start..end].trim().parse().ok()
+}
+
+/// Reads a `JMdict` file (or stdin for `-`) and keeps the selected entries.
+pub(crate) fn subset(path: &str, selection: &Selection) -> Result<Subset> {
+    let mut xml = String::new();
+    indexer::open_input(path)?
+        .reader
+        .read_to_string(&mut xml)
+        .with_context(|| format!("Failed to read {path}"))?;
+
+    let Some(header_end) = xml.find(ENTRY_START) else {
+        bail!("{path} has no entries");
+    };
+    let mut subset = Subset {
+        xml: xml[..header_end].to_string(),
+        entries: 0,
+        missing: Vec::new(),
+    };
+    let mut found = BTreeSet::new();
+
+    let mut position = header_end;
+    let mut count = 0;
+    while let Some(start) = xml[position..].find(ENTRY_START) {
+        let start = position + start;
+        let Some(end) = xml[start..].find(ENTRY_END) else {
+            bail!("{path} ends in the middle of an entry");
+        };
+        let end = start + end + ENTRY_END.len();
+        position = end;
+
+        let entry = &xml[start..end];
+        let id = ent_seq(entry);
+        count += 1;
+        let selected = count <= selection.first || id.is_some_and(|id| selection.ids.contains(&id));
+        if selected {
+            subset.xml.push_str(entry);
+            subset.xml.push('\n');
+            subset.entries += 1;
+            found.extend(id);
+        }
+    }
+    subset.xml.push_str("</JMdict>\n");
+    subset.missing = selection.ids.difference(&found).copied().collect();
+    Ok(subset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subset() {
+        let selection = Selection {
+            ids: BTreeSet::from([1_829_380, 1]),
+            first: 2,
+        };
+        let subset = subset("testdata/JMdict_e_test.gz", &selection).unwrap();
+        assert_eq!(subset.entries, 3);
+        assert_eq!(subset.missing, vec![1]);
+        assert!(subset.xml.contains("<!ENTITY n \""));
+        assert!(subset.xml.contains("<!-- JMdict created: 2023-06-14 -->"));
+        assert!(subset.xml.contains("<pos>&n;</pos>"));
+
+        // The result is valid JMdict, in any compression
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["subset.xml", "subset.xml.gz", "subset.xml.xz"] {
+            let path = dir.path().join(name);
+            indexer::write_compressed(&path, subset.xml.as_bytes()).unwrap();
+            let (entries, _) = indexer::read_entries(path.to_str().unwrap()).unwrap();
+            assert_eq!(entries.len(), 3, "{name}");
+            assert_eq!(entries[&1_829_380].headword(), "赤錆");
+            assert_eq!(entries[&1_829_380].senses[0].glosses, vec!["rust"]);
+        }
+    }
+}
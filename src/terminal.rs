@@ -0,0 +1,307 @@
+//! Terminal size, display width of Japanese text, and paging long output.
+
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+
+nix::ioctl_read_bad!(window_size, nix::libc::TIOCGWINSZ, nix::pty::Winsize);
+
+/// The terminal's size in rows and columns, if stdout is a terminal.
+pub(crate) fn size() -> Option<(usize, usize)> {
+    if !nix::unistd::isatty(nix::libc::STDOUT_FILENO).unwrap_or(false) {
+        return None;
+    }
+    let mut size = nix::pty::Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes a winsize to the pointer it's given
+    unsafe { window_size(nix::libc::STDOUT_FILENO, &raw mut size) }.ok()?;
+    (size.ws_row > 0 && size.ws_col > 0).then(|| (size.ws_row.into(), size.ws_col.into()))
+}
+
+/// Columns taken up by `c`: two for East Asian wide and fullwidth
+/// characters, none for combining marks and control characters.
+fn char_width(c: char) -> usize {
+    match u32::from(c) {
+        0..=0x1f
+        | 0x7f..=0x9f
+        | 0x300..=0x36f
+        | 0x200b..=0x200f
+        | 0x3099..=0x309a
+        | 0xfe00..=0xfe0f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x2fffd
+        | 0x30000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// Skips the rest of an ANSI escape sequence, after the `\x1b`.
+fn skip_escape(chars: &mut impl Iterator<Item = char>, text: &mut String) {
+    for c in chars {
+        text.push(c);
+        if c.is_ascii_alphabetic() {
+            break;
+        }
+    }
+}
+
+/// Columns taken up by `text`, not counting color codes.
+pub(crate) fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            skip_escape(&mut chars, &mut String::new());
+        } else {
+            width += char_width(c);
+        }
+    }
+    width
+}
+
+/// A piece of text that isn't broken across lines.
+struct Word {
+    text: String,
+    width: usize,
+    space_before: bool,
+}
+
+/// Splits `line` where it may be wrapped: at spaces, and before wide
+/// characters, as Japanese has no spaces between words.
+fn words(line: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut word = Word {
+        text: String::new(),
+        width: 0,
+        space_before: false,
+    };
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            word.text.push(c);
+            skip_escape(&mut chars, &mut word.text);
+            continue;
+        }
+        let width = char_width(c);
+        if c == ' ' || (width == 2 && word.width > 0) {
+            let space_before = c == ' ';
+            if word.text.is_empty() {
+                word.space_before |= space_before;
+            } else {
+                let next = Word {
+                    text: String::new(),
+                    width: 0,
+                    space_before,
+                };
+                words.push(std::mem::replace(&mut word, next));
+            }
+            if space_before {
+                continue;
+            }
+        }
+        word.text.push(c);
+        word.width += width;
+    }
+    if !word.text.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Wraps `line` to `width` columns, indenting the lines after the first by
+/// `indent` columns.
+pub(crate) fn wrap(line: &str, width: usize, indent: usize) -> String {
+    let text = line.trim_start_matches(' ');
+    let mut wrapped = line[..line.len() - text.len()].to_string();
+    let mut column = wrapped.len();
+    let mut line_start = true;
+    for word in words(text) {
+        let space = usize::from(word.space_before && !line_start);
+        if !line_start && word.width > 0 && column + space + word.width > width {
+            wrapped.push('\n');
+            wrapped.push_str(&" ".repeat(indent));
+            column = indent;
+            line_start = true;
+        } else if space == 1 {
+            wrapped.push(' ');
+            column += 1;
+        }
+        wrapped.push_str(&word.text);
+        column += word.width;
+        line_start &= word.width == 0;
+    }
+    wrapped
+}
+
+/// Writes to stdout, unless that's a terminal and the output doesn't fit on
+/// the screen: then it's shown in `$PAGER`, or `less -R`.
+pub(crate) struct Pager {
+    /// Size of the terminal, while we don't know yet whether to page
+    screen: Option<(usize, usize)>,
+    buffer: Vec<u8>,
+    /// Screen rows taken up by the complete lines in `buffer`
+    rows: usize,
+    child: Option<Child>,
+}
+
+impl Pager {
+    /// Pages if the terminal's `size` is given.
+    pub(crate) fn new(size: Option<(usize, usize)>) -> Self {
+        Pager {
+            screen: size,
+            buffer: Vec::new(),
+            rows: 0,
+            child: None,
+        }
+    }
+
+    fn spawn(&mut self) -> io::Result<()> {
+        let command = std::env::var("PAGER")
+            .ok()
+            .filter(|pager| !pager.trim().is_empty())
+            .unwrap_or_else(|| "less -R".to_string());
+        let mut pager = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = pager.stdin.as_mut().expect("stdin is piped");
+        ignore_closed(stdin.write_all(&self.buffer))?;
+        self.child = Some(pager);
+        Ok(())
+    }
+
+    /// Writes out what's left, and waits for the pager to be closed.
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(mut pager) = self.child.take() {
+            drop(pager.stdin.take());
+            pager.wait()?;
+            return Ok(());
+        }
+        self.screen = None;
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&std::mem::take(&mut self.buffer))?;
+        stdout.flush()
+    }
+}
+
+/// Output that's still held back is written out even if whatever was
+/// printing fails before calling `finish`.
+impl Drop for Pager {
+    fn drop(&mut self) {
+        self.close().ok();
+    }
+}
+
+/// The pager exits when the user quits it, which doesn't make the output an
+/// error.
+fn ignore_closed(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+impl Write for Pager {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if let Some(pager) = &mut self.child {
+            let stdin = pager.stdin.as_mut().expect("stdin is piped");
+            ignore_closed(stdin.write_all(bytes))?;
+            return Ok(bytes.len());
+        }
+        let Some((rows, columns)) = self.screen else {
+            return io::stdout().write(bytes);
+        };
+
+        let complete = self
+            .buffer
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        self.buffer.extend_from_slice(bytes);
+        let end = self
+            .buffer
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        if end > complete {
+            self.rows += String::from_utf8_lossy(&self.buffer[complete..end - 1])
+                .split('\n')
+                .map(|line| display_width(line).div_ceil(columns).max(1))
+                .sum::<usize>();
+        }
+        // Leave a row for the prompt
+        if self.rows >= rows {
+            self.screen = None;
+            if self.spawn().is_err() {
+                // No pager to be had, so write it all out after all
+                io::stdout().write_all(&std::mem::take(&mut self.buffer))?;
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.child {
+            Some(pager) => ignore_closed(pager.stdin.as_mut().expect("stdin is piped").flush()),
+            None if self.screen.is_none() => io::stdout().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display_width() {
+        assert_eq!(display_width("rust"), 4);
+        assert_eq!(display_width("赤錆 (あかさび)"), 15);
+        assert_eq!(display_width("ｱｶｻﾋﾞ"), 5);
+        assert_eq!(display_width("\x1b[1;34m赤錆\x1b[0m"), 4);
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("1. [noun] rust; red rust; corrosion", 20, 3),
+            "1. [noun] rust; red\n   rust; corrosion"
+        );
+        assert_eq!(wrap("short", 20, 3), "short");
+        // Japanese breaks between characters, counting two columns each
+        assert_eq!(
+            wrap("   See also: 赤錆・あかさび", 20, 3),
+            "   See also: 赤錆・\n   あかさび"
+        );
+        // Color codes take no room
+        assert_eq!(
+            wrap("\x1b[1mrust\x1b[0m; \x1b[1mcorrosion\x1b[0m", 10, 2),
+            "\x1b[1mrust\x1b[0m;\n  \x1b[1mcorrosion\x1b[0m"
+        );
+        // Words longer than a line are left as they are
+        assert_eq!(
+            wrap("a supercalifragilistic b", 10, 0),
+            "a\nsupercalifragilistic\nb"
+        );
+    }
+}
@@ -0,0 +1,651 @@
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// Number of rows per bank file when writing, matching what Yomitan's own tools produce.
+const BANK_SIZE: usize = 10_000;
+
+/// A Yomitan (formerly Yomichan) dictionary, as distributed in a zip archive.
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    pub index: DictionaryIndex,
+    pub terms: Vec<Term>,
+    pub term_meta: Vec<TermMeta>,
+    pub kanji: Vec<Kanji>,
+    pub tags: Vec<Tag>,
+}
+
+/// The contents of `index.json`.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct DictionaryIndex {
+    pub title: String,
+    #[serde(default)]
+    pub revision: String,
+    // Older dictionaries use `version`, newer ones `format`
+    #[serde(alias = "version")]
+    pub format: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// A row from a `term_bank_*.json` file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Term {
+    pub expression: String,
+    pub reading: String,
+    pub definition_tags: Vec<String>,
+    pub rules: Vec<String>,
+    pub score: i64,
+    /// Glossary entries, rendered down to plain text
+    pub glossary: Vec<String>,
+    pub sequence: i64,
+    /// Tags for the term as a whole, rather than a single definition
+    pub tags: Vec<String>,
+}
+
+/// A row from a `term_meta_bank_*.json` file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TermMeta {
+    pub expression: String,
+    pub data: MetaData,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MetaData {
+    Frequency {
+        reading: Option<String>,
+        value: i64,
+    },
+    Pitch {
+        reading: String,
+        positions: Vec<i64>,
+    },
+}
+
+/// A row from a `kanji_bank_*.json` file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Kanji {
+    pub character: String,
+    pub onyomi: Vec<String>,
+    pub kunyomi: Vec<String>,
+    pub tags: Vec<String>,
+    pub meanings: Vec<String>,
+}
+
+/// A row from a `tag_bank_*.json` file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Tag {
+    pub name: String,
+    pub category: String,
+    /// Human readable description of the tag
+    pub notes: String,
+}
+
+impl Dictionary {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to read Yomitan dictionary {}", path.display()))
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<Self> {
+        let mut archive = ZipArchive::new(reader)?;
+
+        let index: DictionaryIndex = serde_json::from_str(&read_entry(&mut archive, "index.json")?)
+            .context("Failed to parse index.json")?;
+        if !matches!(index.format, Some(1..=3)) {
+            bail!("Unsupported dictionary format: {:?}", index.format);
+        }
+
+        let mut dictionary = Dictionary {
+            index,
+            ..Dictionary::default()
+        };
+
+        for name in bank_files(&archive, "term_bank_") {
+            for row in read_bank(&mut archive, &name)? {
+                let term = parse_term(&row).with_context(|| format!("Invalid row in {name}"))?;
+                dictionary.terms.push(term);
+            }
+        }
+        for name in bank_files(&archive, "term_meta_bank_") {
+            for row in read_bank(&mut archive, &name)? {
+                // Unknown modes are skipped so newer dictionaries can still be imported
+                if let Some(meta) =
+                    parse_term_meta(&row).with_context(|| format!("Invalid row in {name}"))?
+                {
+                    dictionary.term_meta.push(meta);
+                }
+            }
+        }
+        for name in bank_files(&archive, "kanji_bank_") {
+            for row in read_bank(&mut archive, &name)? {
+                let kanji = parse_kanji(&row).with_context(|| format!("Invalid row in {name}"))?;
+                dictionary.kanji.push(kanji);
+            }
+        }
+        for name in bank_files(&archive, "tag_bank_") {
+            for row in read_bank(&mut archive, &name)? {
+                let tag = parse_tag(&row).with_context(|| format!("Invalid row in {name}"))?;
+                dictionary.tags.push(tag);
+            }
+        }
+
+        Ok(dictionary)
+    }
+
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+        self.to_writer(BufWriter::new(file))?.flush()?;
+        Ok(())
+    }
+
+    /// Writes the dictionary as a format 3 archive, returning the underlying writer.
+    pub fn to_writer<W: Write + Seek>(&self, writer: W) -> Result<W> {
+        let mut archive = ZipWriter::new(writer);
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+        archive.start_file("index.json", options)?;
+        serde_json::to_writer(
+            &mut archive,
+            &DictionaryIndex {
+                format: Some(3),
+                ..self.index.clone()
+            },
+        )?;
+
+        let terms = self.terms.iter().map(|term| {
+            json!([
+                term.expression,
+                term.reading,
+                term.definition_tags.join(" "),
+                term.rules.join(" "),
+                term.score,
+                term.glossary,
+                term.sequence,
+                term.tags.join(" "),
+            ])
+        });
+        write_banks(&mut archive, options, "term_bank_", terms)?;
+
+        let term_meta = self.term_meta.iter().map(|meta| match &meta.data {
+            MetaData::Frequency {
+                reading: Some(reading),
+                value,
+            } => json!([meta.expression, "freq", {"reading": reading, "frequency": value}]),
+            MetaData::Frequency {
+                reading: None,
+                value,
+            } => json!([meta.expression, "freq", value]),
+            MetaData::Pitch { reading, positions } => json!([
+                meta.expression,
+                "pitch",
+                {
+                    "reading": reading,
+                    "pitches": positions.iter().map(|p| json!({"position": p})).collect::<Vec<_>>(),
+                },
+            ]),
+        });
+        write_banks(&mut archive, options, "term_meta_bank_", term_meta)?;
+
+        let kanji = self.kanji.iter().map(|kanji| {
+            json!([
+                kanji.character,
+                kanji.onyomi.join(" "),
+                kanji.kunyomi.join(" "),
+                kanji.tags.join(" "),
+                kanji.meanings,
+                {},
+            ])
+        });
+        write_banks(&mut archive, options, "kanji_bank_", kanji)?;
+
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| json!([tag.name, tag.category, 0, tag.notes, 0]));
+        write_banks(&mut archive, options, "tag_bank_", tags)?;
+
+        Ok(archive.finish()?)
+    }
+}
+
+fn write_banks<W: Write + Seek>(
+    archive: &mut ZipWriter<W>,
+    options: FileOptions,
+    prefix: &str,
+    rows: impl Iterator<Item = Value>,
+) -> Result<()> {
+    for (idx, chunk) in rows.chunks(BANK_SIZE).into_iter().enumerate() {
+        archive.start_file(format!("{prefix}{}.json", idx + 1), options)?;
+        serde_json::to_writer(&mut *archive, &chunk.collect::<Vec<_>>())?;
+    }
+    Ok(())
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<String> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("Missing {name}"))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn read_bank<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<Value>> {
+    serde_json::from_str(&read_entry(archive, name)?)
+        .with_context(|| format!("Failed to parse {name}"))
+}
+
+/// Returns the names of all `<prefix><n>.json` files, ordered by `n`.
+fn bank_files<R: Read + Seek>(archive: &ZipArchive<R>, prefix: &str) -> Vec<String> {
+    let mut names = archive
+        .file_names()
+        .filter_map(|name| {
+            let n = name.strip_prefix(prefix)?.strip_suffix(".json")?;
+            Some((n.parse::<u32>().ok()?, name.to_string()))
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names.into_iter().map(|(_, name)| name).collect()
+}
+
+fn parse_term(row: &Value) -> Result<Term> {
+    let row = row.as_array().ok_or_else(|| anyhow!("Expected an array"))?;
+
+    // Format 1 is [expression, reading, tags, rules, score, ...glossary], while
+    // format 3 is [expression, reading, tags, rules, score, [glossary], sequence, term_tags]
+    let (glossary, sequence, tags) = match row.get(5) {
+        Some(Value::Array(glossary)) => (
+            glossary.iter().filter_map(render_glossary).collect(),
+            row.get(6).and_then(Value::as_i64).unwrap_or_default(),
+            split_tags(row.get(7)),
+        ),
+        _ => (
+            row.iter().skip(5).filter_map(render_glossary).collect(),
+            0,
+            Vec::new(),
+        ),
+    };
+
+    Ok(Term {
+        expression: string_at(row, 0)?,
+        reading: row
+            .get(1)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        definition_tags: split_tags(row.get(2)),
+        rules: split_tags(row.get(3)),
+        score: row.get(4).and_then(Value::as_i64).unwrap_or_default(),
+        glossary,
+        sequence,
+        tags,
+    })
+}
+
+fn parse_term_meta(row: &Value) -> Result<Option<TermMeta>> {
+    let row = row.as_array().ok_or_else(|| anyhow!("Expected an array"))?;
+    let expression = string_at(row, 0)?;
+    let data = row.get(2).ok_or_else(|| anyhow!("Missing data"))?;
+
+    let data = match row.get(1).and_then(Value::as_str) {
+        Some("freq") => {
+            // Either a bare frequency, or one qualified by reading
+            let (reading, frequency) = match data.get("frequency") {
+                Some(frequency) => (
+                    data.get("reading")
+                        .and_then(Value::as_str)
+                        .map(ToString::to_string),
+                    frequency,
+                ),
+                None => (None, data),
+            };
+            let Some(value) = frequency_value(frequency) else {
+                bail!("Invalid frequency: {frequency}");
+            };
+            MetaData::Frequency { reading, value }
+        }
+        Some("pitch") => MetaData::Pitch {
+            reading: data
+                .get("reading")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            positions: data
+                .get("pitches")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|pitch| pitch.get("position").and_then(Value::as_i64))
+                .collect(),
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(TermMeta { expression, data }))
+}
+
+fn frequency_value(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64().or_else(|| {
+            #[allow(clippy::cast_possible_truncation)]
+            n.as_f64().map(|f| f as i64)
+        }),
+        // Some dictionaries use strings like "1234" or "1234㋕"
+        Value::String(s) => {
+            let digits = s
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>();
+            digits.parse().ok()
+        }
+        Value::Object(o) => o.get("value").and_then(frequency_value),
+        _ => None,
+    }
+}
+
+fn parse_kanji(row: &Value) -> Result<Kanji> {
+    let row = row.as_array().ok_or_else(|| anyhow!("Expected an array"))?;
+
+    // Format 1 has the meanings inline, format 3 as an array followed by stats
+    let meanings = match row.get(4) {
+        Some(Value::Array(meanings)) => meanings
+            .iter()
+            .filter_map(Value::as_str)
+            .map(ToString::to_string)
+            .collect(),
+        _ => row
+            .iter()
+            .skip(4)
+            .filter_map(Value::as_str)
+            .map(ToString::to_string)
+            .collect(),
+    };
+
+    Ok(Kanji {
+        character: string_at(row, 0)?,
+        onyomi: split_tags(row.get(1)),
+        kunyomi: split_tags(row.get(2)),
+        tags: split_tags(row.get(3)),
+        meanings,
+    })
+}
+
+fn parse_tag(row: &Value) -> Result<Tag> {
+    let row = row.as_array().ok_or_else(|| anyhow!("Expected an array"))?;
+
+    Ok(Tag {
+        name: string_at(row, 0)?,
+        category: row
+            .get(1)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        notes: row
+            .get(3)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+fn string_at(row: &[Value], idx: usize) -> Result<String> {
+    row.get(idx)
+        .and_then(Value::as_str)
+        .map(ToString::to_string)
+        .ok_or_else(|| anyhow!("Expected a string at position {idx}"))
+}
+
+fn split_tags(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_str)
+        .map(|s| s.split_whitespace().map(ToString::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Renders a single glossary item to plain text. Images are dropped, as are
+/// items that render to nothing.
+fn render_glossary(item: &Value) -> Option<String> {
+    let text = match item {
+        Value::String(s) => s.clone(),
+        Value::Object(o) => match o.get("type").and_then(Value::as_str) {
+            Some("text") => o.get("text")?.as_str()?.to_string(),
+            Some("structured-content") => render_structured_content(o.get("content")?),
+            _ => return None,
+        },
+        // [uninflected, [rules]], used to point inflected forms at their dictionary form
+        Value::Array(a) => format!("→ {}", a.first()?.as_str()?),
+        _ => return None,
+    };
+
+    let text = text.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Renders Yomitan structured content to plain text suitable for a terminal.
+pub fn render_structured_content(content: &Value) -> String {
+    let mut out = String::new();
+    render_node(content, &mut out);
+
+    // Collapse the blank lines left behind by nested block elements
+    out.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_node(node: &Value, out: &mut String) {
+    match node {
+        Value::String(s) => out.push_str(s),
+        Value::Array(children) => {
+            for child in children {
+                render_node(child, out);
+            }
+        }
+        Value::Object(o) => {
+            let content = o.get("content").unwrap_or(&Value::Null);
+            match o.get("tag").and_then(Value::as_str).unwrap_or_default() {
+                "br" => out.push('\n'),
+                // Furigana is noise without the layout to go with it
+                "rt" | "rp" | "img" => {}
+                "li" => {
+                    out.push_str("\n• ");
+                    render_node(content, out);
+                    out.push('\n');
+                }
+                "td" | "th" => {
+                    render_node(content, out);
+                    out.push(' ');
+                }
+                "div" | "ol" | "ul" | "table" | "thead" | "tbody" | "tfoot" | "tr" | "details"
+                | "summary" => {
+                    out.push('\n');
+                    render_node(content, out);
+                    out.push('\n');
+                }
+                _ => render_node(content, out),
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Builds an in-memory dictionary archive from (file name, JSON) pairs.
+    pub(crate) fn build_archive(files: &[(&str, Value)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, value) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(value.to_string().as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    pub(crate) fn test_archive() -> Vec<u8> {
+        build_archive(&[
+            (
+                "index.json",
+                json!({"title": "Test Dictionary", "revision": "1", "format": 3}),
+            ),
+            (
+                "term_bank_1.json",
+                json!([
+                    ["赤錆", "あかさび", "n", "", 10, ["rust", {"type": "text", "text": "red rust"}], 1, "P"],
+                    ["錆びる", "さびる", "v1", "v1", 5, [{
+                        "type": "structured-content",
+                        "content": [
+                            {"tag": "ruby", "content": ["錆", {"tag": "rt", "content": "さ"}]},
+                            "びる",
+                            {"tag": "ul", "content": [
+                                {"tag": "li", "content": "to rust"},
+                                {"tag": "li", "content": ["to become ", {"tag": "span", "content": "dull"}]}
+                            ]}
+                        ]
+                    }], 2, ""],
+                ]),
+            ),
+            (
+                "term_meta_bank_1.json",
+                json!([
+                    ["赤錆", "freq", 1234],
+                    ["錆びる", "freq", {"reading": "さびる", "frequency": {"value": 567, "displayValue": "567★"}}],
+                    ["赤錆", "pitch", {"reading": "あかさび", "pitches": [{"position": 0}, {"position": 2}]}],
+                ]),
+            ),
+            (
+                "kanji_bank_1.json",
+                json!([["錆", "セイ ショウ", "さび さび.る", "jouyou", ["rust", "tarnish"], {"strokes": "16"}]]),
+            ),
+            (
+                "tag_bank_1.json",
+                json!([["n", "partOfSpeech", -3, "noun (common) (futsuumeishi)", 0]]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let dictionary = Dictionary::from_reader(Cursor::new(test_archive())).unwrap();
+
+        assert_eq!(dictionary.index.title, "Test Dictionary");
+        assert_eq!(
+            dictionary.terms[0],
+            Term {
+                expression: "赤錆".into(),
+                reading: "あかさび".into(),
+                definition_tags: vec!["n".into()],
+                rules: vec![],
+                score: 10,
+                glossary: vec!["rust".into(), "red rust".into()],
+                sequence: 1,
+                tags: vec!["P".into()],
+            }
+        );
+        assert_eq!(
+            dictionary.terms[1].glossary,
+            vec!["錆びる\n• to rust\n• to become dull".to_string()]
+        );
+        assert_eq!(
+            dictionary.term_meta,
+            vec![
+                TermMeta {
+                    expression: "赤錆".into(),
+                    data: MetaData::Frequency {
+                        reading: None,
+                        value: 1234
+                    }
+                },
+                TermMeta {
+                    expression: "錆びる".into(),
+                    data: MetaData::Frequency {
+                        reading: Some("さびる".into()),
+                        value: 567
+                    }
+                },
+                TermMeta {
+                    expression: "赤錆".into(),
+                    data: MetaData::Pitch {
+                        reading: "あかさび".into(),
+                        positions: vec![0, 2]
+                    }
+                },
+            ]
+        );
+        assert_eq!(
+            dictionary.kanji,
+            vec![Kanji {
+                character: "錆".into(),
+                onyomi: vec!["セイ".into(), "ショウ".into()],
+                kunyomi: vec!["さび".into(), "さび.る".into()],
+                tags: vec!["jouyou".into()],
+                meanings: vec!["rust".into(), "tarnish".into()],
+            }]
+        );
+        assert_eq!(
+            dictionary.tags,
+            vec![Tag {
+                name: "n".into(),
+                category: "partOfSpeech".into(),
+                notes: "noun (common) (futsuumeishi)".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_format_1() {
+        let archive = build_archive(&[
+            ("index.json", json!({"title": "Old", "version": 1})),
+            (
+                "term_bank_1.json",
+                json!([["錆", "さび", "n", "", 0, "rust", "tarnish"]]),
+            ),
+            (
+                "kanji_bank_1.json",
+                json!([["錆", "セイ", "さび", "", "rust"]]),
+            ),
+        ]);
+        let dictionary = Dictionary::from_reader(Cursor::new(archive)).unwrap();
+
+        assert_eq!(dictionary.terms[0].glossary, vec!["rust", "tarnish"]);
+        assert_eq!(dictionary.kanji[0].meanings, vec!["rust"]);
+    }
+
+    #[test]
+    fn test_missing_index() {
+        let archive = build_archive(&[("term_bank_1.json", json!([]))]);
+        assert!(Dictionary::from_reader(Cursor::new(archive)).is_err());
+    }
+
+    #[test]
+    fn test_to_writer() {
+        let dictionary = Dictionary::from_reader(Cursor::new(test_archive())).unwrap();
+        let written = dictionary.to_writer(Cursor::new(Vec::new())).unwrap();
+        let read_back = Dictionary::from_reader(Cursor::new(written.into_inner())).unwrap();
+
+        assert_eq!(read_back.index.title, dictionary.index.title);
+        assert_eq!(read_back.terms[0], dictionary.terms[0]);
+        // Structured content is rendered on import, so it comes back as plain text
+        assert_eq!(read_back.terms[1].glossary, dictionary.terms[1].glossary);
+        assert_eq!(read_back.term_meta, dictionary.term_meta);
+        assert_eq!(read_back.kanji, dictionary.kanji);
+        assert_eq!(read_back.tags, dictionary.tags);
+    }
+}